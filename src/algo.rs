@@ -0,0 +1,503 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::instrument::InstrumentStore;
+use crate::order_manager::{OrderAnomaly, OrderManager, OrderState, log_anomaly};
+use crate::server::fantasy::{
+    Algo, MassCancelRequest, ParentOrderReport, ParentOrderRequest, RequestMessage, Side,
+};
+use crate::store::{Journal, Record};
+
+/// Quantities closer than this are equal.
+const EPSILON: f64 = 1e-9;
+
+/// Longest TWAP, a parent order is worked within a day.
+const MAX_DURATION_SECS: u64 = 86_400;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ParentState {
+    Working,
+    Filled,
+    Canceled,
+    /// A child order was rejected or canceled outside the algo, no more are sent.
+    Stopped,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "algo", rename_all = "snake_case")]
+pub enum AlgoParams {
+    Twap { duration_secs: u64, slices: u32 },
+    Iceberg { display_quantity: f64 },
+}
+
+impl AlgoParams {
+    pub fn from_request(req: &ParentOrderRequest) -> Result<Self, String> {
+        match req.algo() {
+            Algo::Twap if req.slices == 0 => Err("TWAP needs slices".to_string()),
+            Algo::Twap if req.duration_secs > MAX_DURATION_SECS => {
+                Err(format!("TWAP duration_secs above {}", MAX_DURATION_SECS))
+            }
+            Algo::Twap => Ok(AlgoParams::Twap {
+                duration_secs: req.duration_secs,
+                slices: req.slices,
+            }),
+            Algo::Iceberg if req.display_quantity <= 0.0 => {
+                Err("iceberg needs a display_quantity".to_string())
+            }
+            Algo::Iceberg => Ok(AlgoParams::Iceberg {
+                display_quantity: req.display_quantity,
+            }),
+            Algo::Unspecified => Err("algo is required".to_string()),
+        }
+    }
+
+    fn algo(&self) -> Algo {
+        match self {
+            AlgoParams::Twap { .. } => Algo::Twap,
+            AlgoParams::Iceberg { .. } => Algo::Iceberg,
+        }
+    }
+}
+
+/// Order the gateway works itself by sending child orders.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ParentOrder {
+    pub parent_id: String,
+    pub client_id: String,
+    pub client_ref: Option<String>,
+    pub account: String,
+    pub symbol: String,
+    /// proto `Side` and `OrdType` numbers, copied to the child orders.
+    pub side: i32,
+    pub ord_type: i32,
+    pub price: f64,
+    pub quantity: f64,
+    /// Custom tags, copied to the child orders.
+    #[serde(default)]
+    pub extra_tags: HashMap<i32, String>,
+    pub params: AlgoParams,
+    pub state: ParentState,
+    /// Cancel requested, no more child orders are sent.
+    pub canceling: bool,
+    pub start_ms: i64,
+    pub slices_sent: u32,
+    /// ClOrdIDs of the child orders, oldest first.
+    pub children: Vec<String>,
+    pub text: Option<String>,
+}
+
+/// Where a parent order stands, from its child orders in the order book.
+#[derive(Debug, Default)]
+struct Progress {
+    cum_qty: f64,
+    avg_px: f64,
+    /// Quantity filled or still working at the broker.
+    committed: f64,
+    live: usize,
+    /// Child order ended without being filled, and not by a parent cancel.
+    stopped_by: Option<String>,
+}
+
+impl ParentOrder {
+    fn progress(&self, om: &OrderManager) -> Progress {
+        let mut progress = Progress::default();
+        let mut notional = 0.0;
+        for order in self.children.iter().filter_map(|id| om.get("", id)) {
+            progress.cum_qty += order.cum_qty;
+            notional += order.cum_qty * order.avg_px;
+            if order.state.is_terminal() {
+                progress.committed += order.cum_qty;
+            } else {
+                progress.committed += order.quantity.max(order.cum_qty);
+                progress.live += 1;
+            }
+            let stopped = order.state == OrderState::Rejected
+                || (order.state == OrderState::Canceled && !self.canceling);
+            if stopped && progress.stopped_by.is_none() {
+                progress.stopped_by = Some(format!("child {} {:?}", order.cl_ord_id, order.state));
+            }
+        }
+        if progress.cum_qty > 0.0 {
+            progress.avg_px = notional / progress.cum_qty;
+        }
+        progress
+    }
+
+    fn child_request(&self, quantity: f64) -> RequestMessage {
+        RequestMessage {
+            client_id: self.client_id.clone(),
+            account: self.account.clone(),
+            symbol: self.symbol.clone(),
+            side: self.side,
+            ord_type: self.ord_type,
+            price: self.price,
+            quantity,
+            extra_tags: self.extra_tags.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Quantity of the next child order due at `now_ms`, if any. TWAP slices
+    /// are multiples of `lot`.
+    fn next_slice(&mut self, remaining: f64, live: usize, now_ms: i64, lot: f64) -> Option<f64> {
+        match self.params {
+            AlgoParams::Twap {
+                duration_secs,
+                slices,
+            } => {
+                // saturating, journaled parent orders were not checked against MAX_DURATION_SECS
+                let offset_ms = duration_secs
+                    .saturating_mul(1000)
+                    .saturating_mul(self.slices_sent as u64)
+                    / slices as u64;
+                let due = self
+                    .start_ms
+                    .saturating_add(i64::try_from(offset_ms).unwrap_or(i64::MAX));
+                if self.slices_sent >= slices || now_ms < due {
+                    return None;
+                }
+                self.slices_sent += 1;
+                if self.slices_sent == slices {
+                    return Some(remaining);
+                }
+                // whole lots, the last slice takes what is left
+                let slice = (remaining / (slices - self.slices_sent + 1) as f64 / lot + EPSILON)
+                    .floor()
+                    * lot;
+                (slice > 0.0).then_some(slice)
+            }
+            AlgoParams::Iceberg { display_quantity } => {
+                (live == 0).then(|| display_quantity.min(remaining))
+            }
+        }
+    }
+}
+
+/// Parent orders worked by the gateway, with TWAP or iceberg child orders.
+///
+/// Fills and status of a parent order are aggregated from its child orders
+/// in the order book, `step` sends the child orders that are due.
+pub struct ParentBook {
+    parents: HashMap<String, ParentOrder>,
+    journal: Arc<Journal>,
+    /// Lot sizes of the child orders, 1 for symbols without one.
+    instruments: Arc<InstrumentStore>,
+}
+
+impl ParentBook {
+    pub fn new(journal: Arc<Journal>, instruments: Arc<InstrumentStore>) -> Self {
+        ParentBook {
+            parents: HashMap::new(),
+            journal,
+            instruments,
+        }
+    }
+
+    pub fn restore(&mut self, parents: Vec<ParentOrder>) {
+        for parent in parents {
+            self.parents.insert(parent.parent_id.clone(), parent);
+        }
+    }
+
+    /// Looks up a parent order by id or by the client's reference.
+    pub fn get(&self, client_id: &str, id: &str) -> Option<&ParentOrder> {
+        self.parents.get(id).or_else(|| {
+            self.parents
+                .values()
+                .find(|p| p.client_id == client_id && p.client_ref.as_deref() == Some(id))
+        })
+    }
+
+    pub fn add(
+        &mut self,
+        om: &mut OrderManager,
+        req: &ParentOrderRequest,
+        params: AlgoParams,
+        now_ms: i64,
+    ) -> Result<String, OrderAnomaly> {
+        let order = req.order.clone().unwrap_or_default();
+        let client_ref = (!order.message.is_empty()).then(|| order.message.clone());
+        if let Some(used) = client_ref
+            .as_ref()
+            .filter(|r| self.get(&order.client_id, r).is_some())
+        {
+            return Err(OrderAnomaly::DuplicateClientRef(used.clone()));
+        }
+        let parent = ParentOrder {
+            parent_id: om.next_cl_ord_id()?,
+            client_id: order.client_id.clone(),
+            client_ref,
+            account: order.account.clone(),
+            symbol: order.symbol.clone(),
+            side: order.side,
+            ord_type: order.ord_type,
+            price: order.price,
+            quantity: order.quantity,
+            extra_tags: order.extra_tags.clone(),
+            params,
+            state: ParentState::Working,
+            canceling: false,
+            start_ms: now_ms,
+            slices_sent: 0,
+            children: Vec::new(),
+            text: None,
+        };
+        info!("parent order {} {:?}", parent.parent_id, parent.params);
+        self.journal
+            .append(&Record::Parent(parent.clone()))
+            .map_err(|e| OrderAnomaly::Persistence(e.to_string()))?;
+        let parent_id = parent.parent_id.clone();
+        self.parents.insert(parent_id.clone(), parent);
+        Ok(parent_id)
+    }
+
+    /// Stops a parent order and books a cancel for each of its working child
+    /// orders, the requests are returned to be sent.
+    pub fn cancel(
+        &mut self,
+        om: &mut OrderManager,
+        client_id: &str,
+        id: &str,
+    ) -> Result<(String, Vec<RequestMessage>), OrderAnomaly> {
+        let parent_id = self
+            .get(client_id, id)
+            .map(|p| p.parent_id.clone())
+            .ok_or_else(|| OrderAnomaly::UnknownOrder(id.to_string()))?;
+        let parent = self.parents.get_mut(&parent_id).unwrap();
+        parent.canceling = true;
+        let mut cancels = Vec::new();
+        for child in &parent.children {
+            let Some(order) = om.get("", child) else {
+                continue;
+            };
+            if order.state.is_terminal() || order.state == OrderState::PendingCancel {
+                continue;
+            }
+            let mut req = order.cancel_request();
+            if log_anomaly(om.add_cancel(&mut req)).is_some() {
+                cancels.push(req);
+            }
+        }
+        self.save(&parent_id);
+        Ok((parent_id, cancels))
+    }
+
+    /// Cancels, as `cancel` does, the working parent orders matching a mass
+    /// cancel filter, the child cancels are returned to be sent.
+    pub fn mass_cancel(
+        &mut self,
+        om: &mut OrderManager,
+        filter: &MassCancelRequest,
+    ) -> Vec<RequestMessage> {
+        let mut matching: Vec<String> = self
+            .parents
+            .values()
+            .filter(|p| p.state == ParentState::Working && !p.canceling)
+            .filter(|p| filter.account.is_empty() || p.account == filter.account)
+            .filter(|p| filter.symbol.is_empty() || p.symbol == filter.symbol)
+            .filter(|p| filter.side() == Side::Unspecified || p.side == filter.side)
+            .filter(|p| filter.client_id.is_empty() || p.client_id == filter.client_id)
+            .map(|p| p.parent_id.clone())
+            .collect();
+        matching.sort();
+        let mut cancels = Vec::new();
+        for parent_id in matching {
+            if let Some((_, children)) = log_anomaly(self.cancel(om, "", &parent_id)) {
+                info!(
+                    "parent order {} canceled, {} child cancels",
+                    parent_id,
+                    children.len()
+                );
+                cancels.extend(children);
+            }
+        }
+        cancels
+    }
+
+    /// Updates the state of the working parent orders from their children and
+    /// books the child orders due at `now_ms`, which are returned to be sent.
+    /// `blocked` parent orders (halted account, restricted symbol) send no
+    /// child orders.
+    pub fn step(
+        &mut self,
+        om: &mut OrderManager,
+        now_ms: i64,
+        blocked: impl Fn(&ParentOrder) -> bool,
+    ) -> Vec<RequestMessage> {
+        let mut children = Vec::new();
+        let mut changed = Vec::new();
+        for parent in self.parents.values_mut() {
+            if parent.state != ParentState::Working {
+                continue;
+            }
+            let progress = parent.progress(om);
+            let done = if progress.cum_qty >= parent.quantity - EPSILON {
+                Some(ParentState::Filled)
+            } else if parent.canceling && progress.live == 0 {
+                Some(ParentState::Canceled)
+            } else if progress.stopped_by.is_some() && progress.live == 0 {
+                Some(ParentState::Stopped)
+            } else {
+                None
+            };
+            if let Some(state) = done {
+                info!("parent order {} {:?}", parent.parent_id, state);
+                parent.state = state;
+                if state == ParentState::Stopped {
+                    parent.text = progress.stopped_by;
+                }
+                changed.push(parent.parent_id.clone());
+                continue;
+            }
+            if parent.canceling || progress.stopped_by.is_some() || blocked(parent) {
+                continue;
+            }
+
+            let remaining = parent.quantity - progress.committed;
+            if remaining <= EPSILON {
+                continue;
+            }
+            let lot = self
+                .instruments
+                .get(&parent.symbol)
+                .and_then(|i| i.lot_size)
+                .filter(|lot| *lot > 0.0)
+                .unwrap_or(1.0);
+            let (slices_sent, children_sent) = (parent.slices_sent, parent.children.len());
+            if let Some(quantity) = parent.next_slice(remaining, progress.live, now_ms, lot) {
+                let mut req = parent.child_request(quantity);
+                match om.add_order(&mut req) {
+                    Ok(cl_ord_id) => {
+                        info!(
+                            "parent order {} child {} quantity {}",
+                            parent.parent_id, cl_ord_id, quantity
+                        );
+                        parent.children.push(cl_ord_id);
+                        children.push(req);
+                    }
+                    Err(e) => {
+                        warn!("parent order {} stopped: {}", parent.parent_id, e);
+                        parent.state = ParentState::Stopped;
+                        parent.text = Some(e.to_string());
+                    }
+                }
+            }
+            if parent.slices_sent != slices_sent
+                || parent.children.len() != children_sent
+                || parent.state != ParentState::Working
+            {
+                changed.push(parent.parent_id.clone());
+            }
+        }
+        changed.dedup();
+        for parent_id in changed {
+            self.save(&parent_id);
+        }
+        children
+    }
+
+    /// Working parent orders with the quantity they have still to send.
+    pub fn unsent(&self, om: &OrderManager) -> Vec<(&ParentOrder, f64)> {
+        self.parents
+            .values()
+            .filter(|p| p.state == ParentState::Working && !p.canceling)
+            .map(|p| (p, (p.quantity - p.progress(om).committed).max(0.0)))
+            .collect()
+    }
+
+    pub fn report(&self, parent: &ParentOrder, om: &OrderManager) -> ParentOrderReport {
+        let progress = parent.progress(om);
+        ParentOrderReport {
+            parent_id: parent.parent_id.clone(),
+            client_ref: parent.client_ref.clone().unwrap_or_default(),
+            algo: parent.params.algo() as i32,
+            account: parent.account.clone(),
+            symbol: parent.symbol.clone(),
+            side: parent.side,
+            price: parent.price,
+            quantity: parent.quantity,
+            state: format!("{:?}", parent.state),
+            cum_qty: progress.cum_qty,
+            leaves_qty: (parent.quantity - progress.cum_qty).max(0.0),
+            avg_px: progress.avg_px,
+            text: parent.text.clone().unwrap_or_default(),
+            children: parent
+                .children
+                .iter()
+                .filter_map(|id| om.get("", id))
+                .map(|o| o.to_report())
+                .collect(),
+        }
+    }
+
+    fn save(&self, parent_id: &str) {
+        let record = Record::Parent(self.parents[parent_id].clone());
+        if let Err(e) = self.journal.append(&record) {
+            error!("journal parent order {} failed: {}", parent_id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn twap(quantity: f64, slices: u32) -> ParentOrder {
+        ParentOrder {
+            parent_id: "P1".to_string(),
+            client_id: "c1".to_string(),
+            client_ref: None,
+            account: String::new(),
+            symbol: "USDJPY".to_string(),
+            side: 1,
+            ord_type: 2,
+            price: 150.0,
+            quantity,
+            extra_tags: HashMap::new(),
+            params: AlgoParams::Twap {
+                duration_secs: 0,
+                slices,
+            },
+            state: ParentState::Working,
+            canceling: false,
+            start_ms: 0,
+            slices_sent: 0,
+            children: Vec::new(),
+            text: None,
+        }
+    }
+
+    #[test]
+    fn twap_slices_are_whole_lots() {
+        let mut parent = twap(2500.0, 3);
+        assert_eq!(parent.next_slice(2500.0, 0, 0, 100.0), Some(800.0));
+        assert_eq!(parent.next_slice(1700.0, 1, 0, 100.0), Some(800.0));
+        // the last slice takes the remainder
+        assert_eq!(parent.next_slice(900.0, 2, 0, 100.0), Some(900.0));
+        assert_eq!(parent.next_slice(0.0, 3, 0, 100.0), None);
+    }
+
+    #[test]
+    fn twap_duration_is_bounded() {
+        let request = |duration_secs| ParentOrderRequest {
+            algo: Algo::Twap as i32,
+            duration_secs,
+            slices: 2,
+            ..Default::default()
+        };
+        assert!(AlgoParams::from_request(&request(MAX_DURATION_SECS)).is_ok());
+        assert!(AlgoParams::from_request(&request(u64::MAX)).is_err());
+
+        // one journaled before the bound does not overflow its schedule
+        let mut parent = twap(1000.0, 2);
+        parent.params = AlgoParams::Twap {
+            duration_secs: u64::MAX,
+            slices: 2,
+        };
+        assert_eq!(parent.next_slice(1000.0, 0, 0, 1.0), Some(500.0));
+        assert_eq!(parent.next_slice(500.0, 1, i64::MAX - 1, 1.0), None);
+    }
+}
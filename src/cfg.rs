@@ -1,11 +1,156 @@
+use std::collections::HashMap;
+
 use serde::Deserialize;
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
 pub enum BrokerName {
     Broker1,
     Broker2,
 }
 
+/// Duplicate order check of one client.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct DuplicateRule {
+    #[serde(default)]
+    pub enabled: bool,
+    /// How long a client reference is remembered in seconds, 0 for the trading day.
+    #[serde(default)]
+    pub ttl_secs: u64,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct DuplicateCheckCfg {
+    /// File keeping the seen client references across restarts, empty keeps them in memory.
+    #[serde(default)]
+    pub file: String,
+    /// UTC time `HH:MM:SS` the trading day starts, midnight if empty.
+    #[serde(default)]
+    pub trading_day_start: String,
+    /// Rule of clients not listed in `clients`.
+    #[serde(default)]
+    pub default: DuplicateRule,
+    #[serde(default)]
+    pub clients: HashMap<String, DuplicateRule>,
+}
+
+/// Cancel-on-disconnect of one client.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct CancelOnDisconnectRule {
+    #[serde(default)]
+    pub enabled: bool,
+    /// How long in ms the client may be silent before its open orders are canceled.
+    #[serde(default)]
+    pub grace_ms: u64,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct CancelOnDisconnectCfg {
+    /// Rule of clients not listed in `clients`.
+    #[serde(default)]
+    pub default: CancelOnDisconnectRule,
+    #[serde(default)]
+    pub clients: HashMap<String, CancelOnDisconnectRule>,
+}
+
+/// Pre-trade limits, a missing limit is not checked.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct RiskLimits {
+    #[serde(default)]
+    pub max_order_qty: Option<f64>,
+    /// Quantity times price, or times the reference price for market orders.
+    #[serde(default)]
+    pub max_notional: Option<f64>,
+    /// Largest distance in percent of a limit price from the reference (mark) price.
+    #[serde(default)]
+    pub price_collar_pct: Option<f64>,
+    /// `Market` and/or `Limit`.
+    #[serde(default)]
+    pub allowed_ord_types: Option<Vec<String>>,
+}
+
+/// An order has to pass the `default` limits and those of its account and symbol.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct RiskCfg {
+    #[serde(default)]
+    pub default: RiskLimits,
+    #[serde(default)]
+    pub accounts: HashMap<String, RiskLimits>,
+    #[serde(default)]
+    pub symbols: HashMap<String, RiskLimits>,
+    #[serde(default)]
+    pub credit: CreditCfg,
+}
+
+/// Largest exposure of an account: notional of its open orders plus its
+/// filled positions. A missing limit is not checked.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct CreditCfg {
+    /// Limit of accounts not listed in `accounts`.
+    #[serde(default)]
+    pub default: Option<f64>,
+    #[serde(default)]
+    pub accounts: HashMap<String, f64>,
+}
+
+/// What happens to a message the throttle holds back.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ThrottleBreach {
+    /// Every message waits for its turn.
+    #[default]
+    Queue,
+    /// New orders and replaces are rejected, cancels wait for their turn.
+    RejectOrders,
+}
+
+/// Outbound message rate of the FIX session.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct ThrottleCfg {
+    /// 0 for no throttle.
+    #[serde(default)]
+    pub messages_per_sec: f64,
+    /// Messages that may go out at once after a quiet period, at least 1.
+    #[serde(default)]
+    pub burst: u32,
+    #[serde(default)]
+    pub on_breach: ThrottleBreach,
+}
+
+/// What happens to a new order that would trade against a resting order of
+/// another account of the same beneficial owner.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SelfTradeAction {
+    RejectNew,
+    CancelResting,
+    /// The order goes out, the cross is logged and journaled.
+    #[default]
+    Allow,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct SelfTradeCfg {
+    #[serde(default)]
+    pub action: SelfTradeAction,
+    /// Beneficial owner to its accounts.
+    #[serde(default)]
+    pub owners: HashMap<String, Vec<String>>,
+}
+
+/// Retention of the execution events pushed to `ServerStream`.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct EventLogCfg {
+    /// Events kept in memory, 0 for 10000.
+    #[serde(default)]
+    pub max_events: usize,
+    /// How long an event is kept in memory in seconds, 0 for no limit.
+    #[serde(default)]
+    pub max_age_secs: u64,
+    /// File older events are moved to, empty drops them.
+    #[serde(default)]
+    pub spill_file: String,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct GwConfig {
     pub address: String,
@@ -16,4 +161,47 @@ pub struct GwConfig {
     pub interval: u64,
     pub plugin_cfg_file: String,
     pub broker_name: BrokerName,
+    /// Poll interval in ms of the plugin config file for hot reload, 0 disables it.
+    #[serde(default)]
+    pub plugin_reload_interval: u64,
+    /// Instrument master, `.csv` or `.yaml`. Empty disables symbology translation.
+    #[serde(default)]
+    pub instrument_file: String,
+    /// Prefix of the ClOrdIDs generated by the gateway.
+    #[serde(default)]
+    pub cl_ord_id_prefix: String,
+    /// File keeping the ClOrdID counter across restarts, empty keeps it in memory.
+    #[serde(default)]
+    pub cl_ord_id_file: String,
+    #[serde(default)]
+    pub duplicate_check: DuplicateCheckCfg,
+    /// How long in seconds the outcome of a request is kept for its idempotency key, 0 disables it.
+    #[serde(default)]
+    pub idempotency_retention_secs: u64,
+    /// Journal of orders, fills and execution events replayed on start, empty keeps them in memory.
+    #[serde(default)]
+    pub store_file: String,
+    /// How long in ms `MassCancel` waits for the cancels to be acknowledged or rejected.
+    #[serde(default)]
+    pub mass_cancel_timeout_ms: u64,
+    #[serde(default)]
+    pub cancel_on_disconnect: CancelOnDisconnectCfg,
+    #[serde(default)]
+    pub risk: RiskCfg,
+    #[serde(default)]
+    pub throttle: ThrottleCfg,
+    /// Restricted and watch list symbols per account group (YAML), empty keeps
+    /// them in memory.
+    #[serde(default)]
+    pub symbol_lists_file: String,
+    /// Execution events kept for `ServerStream` subscribers to catch up.
+    #[serde(default)]
+    pub event_log: EventLogCfg,
+    #[serde(default)]
+    pub self_trade: SelfTradeCfg,
+    /// Operator name to the token admin RPCs (KillSwitch, Resume,
+    /// UpdateSymbolList, SetMarkPrice) are authorized with, sent as
+    /// `authorization: Bearer <token>` metadata. Empty, they are refused.
+    #[serde(default)]
+    pub admin_tokens: HashMap<String, String>,
 }
@@ -0,0 +1,69 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use log::info;
+
+/// Allocates the ClOrdIDs the gateway sends to the broker.
+///
+/// An id is `<prefix><yyyymmdd>-<counter>`, the counter never goes back and is
+/// written to `state_file` before the id is handed out, so a restart cannot
+/// reuse an id already sent.
+#[derive(Debug)]
+pub struct ClOrdIdGenerator {
+    prefix: String,
+    state_file: String,
+    counter: u64,
+}
+
+impl ClOrdIdGenerator {
+    /// Restores the counter from `state_file`, a missing file starts from zero.
+    /// An empty path keeps the counter in memory only.
+    pub fn load(prefix: &str, state_file: &str) -> io::Result<Self> {
+        if state_file.is_empty() {
+            return Ok(ClOrdIdGenerator {
+                prefix: prefix.to_string(),
+                state_file: String::new(),
+                counter: 0,
+            });
+        }
+        let counter = match fs::read_to_string(state_file) {
+            Ok(content) => content.trim().parse().map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("{state_file}: {e}"))
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+        info!("ClOrdID counter {} restored from {}", counter, state_file);
+        Ok(ClOrdIdGenerator {
+            prefix: prefix.to_string(),
+            state_file: state_file.to_string(),
+            counter,
+        })
+    }
+
+    pub fn next_id(&mut self) -> io::Result<String> {
+        let counter = self.counter + 1;
+        self.persist(counter)?;
+        self.counter = counter;
+        Ok(format!(
+            "{}{}-{}",
+            self.prefix,
+            chrono::Utc::now().format("%Y%m%d"),
+            counter
+        ))
+    }
+
+    fn persist(&self, counter: u64) -> io::Result<()> {
+        if self.state_file.is_empty() {
+            return Ok(());
+        }
+        if let Some(dir) = Path::new(&self.state_file).parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // write then rename so a crash never leaves a truncated counter
+        let tmp = format!("{}.tmp", self.state_file);
+        fs::write(&tmp, counter.to_string())?;
+        fs::rename(&tmp, &self.state_file)
+    }
+}
@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::cfg::{CancelOnDisconnectCfg, CancelOnDisconnectRule};
+
+#[derive(Debug)]
+struct Liveness {
+    last_seen: Instant,
+    /// Open bidirectional streams, the client is alive while one is open.
+    streams: usize,
+    /// Cleared once the orders are canceled, until the client comes back.
+    armed: bool,
+}
+
+/// Last activity of each gRPC client, for the clients that opted in to
+/// cancel-on-disconnect.
+///
+/// A client is watched from its first heartbeat, stream message or order
+/// request. Once it has no stream open and was silent for longer than its
+/// grace period it is reported by `disconnected`, once.
+#[derive(Debug)]
+pub struct ClientMonitor {
+    cfg: CancelOnDisconnectCfg,
+    clients: HashMap<String, Liveness>,
+}
+
+impl ClientMonitor {
+    pub fn new(cfg: &CancelOnDisconnectCfg) -> Self {
+        ClientMonitor {
+            cfg: cfg.clone(),
+            clients: HashMap::new(),
+        }
+    }
+
+    fn rule(&self, client_id: &str) -> &CancelOnDisconnectRule {
+        self.cfg.clients.get(client_id).unwrap_or(&self.cfg.default)
+    }
+
+    fn liveness(&mut self, client_id: &str) -> Option<&mut Liveness> {
+        if client_id.is_empty() || !self.rule(client_id).enabled {
+            return None;
+        }
+        let liveness = self
+            .clients
+            .entry(client_id.to_string())
+            .or_insert_with(|| Liveness {
+                last_seen: Instant::now(),
+                streams: 0,
+                armed: true,
+            });
+        liveness.last_seen = Instant::now();
+        liveness.armed = true;
+        Some(liveness)
+    }
+
+    pub fn touch(&mut self, client_id: &str) {
+        self.liveness(client_id);
+    }
+
+    pub fn stream_opened(&mut self, client_id: &str) {
+        if let Some(liveness) = self.liveness(client_id) {
+            liveness.streams += 1;
+        }
+    }
+
+    pub fn stream_closed(&mut self, client_id: &str) {
+        if let Some(liveness) = self.liveness(client_id) {
+            liveness.streams = liveness.streams.saturating_sub(1);
+        }
+    }
+
+    /// Clients gone for longer than their grace period, with how long they
+    /// have been silent.
+    pub fn disconnected(&mut self) -> Vec<(String, Duration)> {
+        let mut gone = Vec::new();
+        for (client_id, liveness) in &mut self.clients {
+            let grace = Duration::from_millis(
+                self.cfg
+                    .clients
+                    .get(client_id)
+                    .unwrap_or(&self.cfg.default)
+                    .grace_ms,
+            );
+            let silent = liveness.last_seen.elapsed();
+            if liveness.armed && liveness.streams == 0 && silent > grace {
+                liveness.armed = false;
+                gone.push((client_id.clone(), silent));
+            }
+        }
+        gone
+    }
+}
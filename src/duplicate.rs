@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::Path;
+
+use chrono::{DateTime, Duration, NaiveDate, NaiveTime, Utc};
+use log::info;
+
+use crate::cfg::{DuplicateCheckCfg, DuplicateRule};
+
+/// Client references already used for an order, per client.
+///
+/// A reference is remembered for the trading day or the TTL of the client's
+/// rule. Seen references are appended to `file` and read back on start, so a
+/// restart does not let a duplicate through.
+pub struct DuplicateFilter {
+    cfg: DuplicateCheckCfg,
+    day_start: NaiveTime,
+    /// (client id, client reference) -> first seen
+    seen: HashMap<(String, String), DateTime<Utc>>,
+    journal: Option<csv::Writer<File>>,
+    next_purge: DateTime<Utc>,
+}
+
+impl DuplicateFilter {
+    pub fn load(cfg: &DuplicateCheckCfg) -> io::Result<Self> {
+        let day_start = match cfg.trading_day_start.as_str() {
+            "" => NaiveTime::MIN,
+            start => NaiveTime::parse_from_str(start, "%H:%M:%S").map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("trading_day_start {start}: {e}"),
+                )
+            })?,
+        };
+        let mut filter = DuplicateFilter {
+            cfg: cfg.clone(),
+            day_start,
+            seen: HashMap::new(),
+            journal: None,
+            next_purge: Utc::now(),
+        };
+        if cfg.file.is_empty() {
+            return Ok(filter);
+        }
+
+        if Path::new(&cfg.file).exists() {
+            let mut reader = csv::ReaderBuilder::new()
+                .has_headers(false)
+                .from_path(&cfg.file)?;
+            for record in reader.deserialize() {
+                let (millis, client_id, client_ref): (i64, String, String) = record?;
+                if let Some(seen) = DateTime::from_timestamp_millis(millis) {
+                    filter.seen.insert((client_id, client_ref), seen);
+                }
+            }
+        }
+        filter.purge(Utc::now(), true)?;
+        info!(
+            "{} client references restored from {}",
+            filter.seen.len(),
+            cfg.file
+        );
+        Ok(filter)
+    }
+
+    fn rule(&self, client_id: &str) -> &DuplicateRule {
+        self.cfg.clients.get(client_id).unwrap_or(&self.cfg.default)
+    }
+
+    pub fn is_duplicate(&self, client_id: &str, client_ref: &str) -> bool {
+        let rule = self.rule(client_id);
+        let key = (client_id.to_string(), client_ref.to_string());
+        rule.enabled
+            && self
+                .seen
+                .get(&key)
+                .is_some_and(|seen| !self.expired(rule, *seen, Utc::now()))
+    }
+
+    /// Remembers a client reference, it is on disk before this returns.
+    pub fn record(&mut self, client_id: &str, client_ref: &str) -> io::Result<()> {
+        let now = Utc::now();
+        if now >= self.next_purge {
+            self.purge(now, false)?;
+        }
+        if !self.rule(client_id).enabled {
+            return Ok(());
+        }
+        if let Some(journal) = &mut self.journal {
+            journal.serialize((now.timestamp_millis(), client_id, client_ref))?;
+            journal.flush()?;
+        }
+        self.seen
+            .insert((client_id.to_string(), client_ref.to_string()), now);
+        Ok(())
+    }
+
+    fn expired(&self, rule: &DuplicateRule, seen: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+        if rule.ttl_secs > 0 {
+            now - seen >= Duration::seconds(rule.ttl_secs as i64)
+        } else {
+            self.trading_day(seen) != self.trading_day(now)
+        }
+    }
+
+    fn trading_day(&self, time: DateTime<Utc>) -> NaiveDate {
+        (time.naive_utc() - (self.day_start - NaiveTime::MIN)).date()
+    }
+
+    /// Drops expired references, the file is rewritten when some were dropped.
+    fn purge(&mut self, now: DateTime<Utc>, rewrite: bool) -> io::Result<()> {
+        self.next_purge = now + Duration::minutes(1);
+        let mut seen = std::mem::take(&mut self.seen);
+        let before = seen.len();
+        seen.retain(|(client_id, _), time| {
+            let rule = self.rule(client_id);
+            rule.enabled && !self.expired(rule, *time, now)
+        });
+        self.seen = seen;
+        if self.cfg.file.is_empty() || !(rewrite || self.seen.len() < before) {
+            return Ok(());
+        }
+
+        if let Some(dir) = Path::new(&self.cfg.file).parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = format!("{}.tmp", self.cfg.file);
+        let mut writer = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_path(&tmp)?;
+        for ((client_id, client_ref), time) in &self.seen {
+            writer.serialize((time.timestamp_millis(), client_id, client_ref))?;
+        }
+        writer.flush()?;
+        fs::rename(&tmp, &self.cfg.file)?;
+        let file = OpenOptions::new().append(true).open(&self.cfg.file)?;
+        self.journal = Some(
+            csv::WriterBuilder::new()
+                .has_headers(false)
+                .from_writer(file),
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(trading_day_start: &str, ttl_secs: u64) -> DuplicateFilter {
+        DuplicateFilter::load(&DuplicateCheckCfg {
+            trading_day_start: trading_day_start.to_string(),
+            default: DuplicateRule {
+                enabled: true,
+                ttl_secs,
+            },
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn at(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time).unwrap().to_utc()
+    }
+
+    #[test]
+    fn trading_day_rolls_over_at_its_start() {
+        let filter = filter("21:00:00", 0);
+        let rule = filter.rule("c1").clone();
+        let seen = at("2025-03-03T20:59:00Z");
+        assert!(filter.expired(&rule, seen, at("2025-03-03T21:00:00Z")));
+        assert!(!filter.expired(&rule, seen, at("2025-03-03T20:59:59Z")));
+        // after the start, the trading day runs into the next calendar day
+        let seen = at("2025-03-03T21:01:00Z");
+        assert!(!filter.expired(&rule, seen, at("2025-03-04T20:59:00Z")));
+        assert!(filter.expired(&rule, seen, at("2025-03-04T21:00:00Z")));
+    }
+
+    #[test]
+    fn ttl_overrides_the_trading_day() {
+        let filter = filter("", 60);
+        let rule = filter.rule("c1").clone();
+        let seen = at("2025-03-03T23:59:30Z");
+        assert!(!filter.expired(&rule, seen, at("2025-03-04T00:00:10Z")));
+        assert!(filter.expired(&rule, seen, at("2025-03-04T00:00:30Z")));
+    }
+
+    #[test]
+    fn recorded_references_are_duplicates() {
+        let mut filter = filter("", 0);
+        filter.record("c1", "r1").unwrap();
+        assert!(filter.is_duplicate("c1", "r1"));
+        assert!(!filter.is_duplicate("c2", "r1"));
+        assert!(!filter.is_duplicate("c1", "r2"));
+    }
+}
@@ -0,0 +1,346 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::cfg::EventLogCfg;
+
+/// Events kept in memory when `max_events` is not set.
+const DEFAULT_MAX_EVENTS: usize = 10_000;
+
+/// Live events buffered per subscriber, one that falls further behind gets
+/// the rest from the log.
+const LIVE_BUFFER: usize = 1024;
+
+/// Execution event pushed to stream subscribers, numbered from 1.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event {
+    pub seq: u64,
+    pub time_ms: i64,
+    pub message: String,
+}
+
+/// Every how many spilled events the offset is indexed, a lookup reads at
+/// most this many lines past the indexed one.
+const INDEX_STRIDE: u64 = 256;
+
+/// Events evicted from memory, one JSON line each in sequence order, kept
+/// across restarts. Every `INDEX_STRIDE`th event is indexed with its offset,
+/// so an event is found by its sequence number without a scan.
+struct Spill {
+    path: String,
+    file: File,
+    /// Sequence number and offset of every `INDEX_STRIDE`th event.
+    index: Vec<(u64, u64)>,
+    count: u64,
+    last_seq: u64,
+    len: u64,
+}
+
+impl Spill {
+    /// Opens the spill file at `path` and indexes the events already in it.
+    /// A line cut short by a crash is cut off.
+    fn open(path: &str) -> io::Result<Self> {
+        if let Some(dir) = Path::new(path).parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut spill = Spill {
+            path: path.to_string(),
+            file,
+            index: Vec::new(),
+            count: 0,
+            last_seq: 0,
+            len: 0,
+        };
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            if read == 0 {
+                break;
+            }
+            let event = match line.ends_with('\n') {
+                true => serde_json::from_str::<Event>(&line).ok(),
+                false => None,
+            };
+            let Some(event) = event else {
+                warn!("{} cut short at offset {}", path, spill.len);
+                spill.file.set_len(spill.len)?;
+                break;
+            };
+            spill.indexed(event.seq, read as u64);
+        }
+        Ok(spill)
+    }
+
+    fn indexed(&mut self, seq: u64, line_len: u64) {
+        if self.count.is_multiple_of(INDEX_STRIDE) {
+            self.index.push((seq, self.len));
+        }
+        self.count += 1;
+        self.last_seq = seq;
+        self.len += line_len;
+    }
+
+    /// Appends an event, one already spilled is skipped.
+    fn append(&mut self, event: &Event) -> io::Result<()> {
+        if self.count > 0 && event.seq <= self.last_seq {
+            return Ok(());
+        }
+        let mut line = serde_json::to_string(event)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.indexed(event.seq, line.len() as u64);
+        Ok(())
+    }
+
+    /// Up to `limit` events from `seq` on, before `until`.
+    fn read(&self, seq: u64, until: u64, limit: usize) -> io::Result<Vec<Event>> {
+        let i = self
+            .index
+            .partition_point(|&(indexed, _)| indexed <= seq)
+            .saturating_sub(1);
+        let Some(&(_, offset)) = self.index.get(i) else {
+            return Ok(Vec::new());
+        };
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut events = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let event: Event = serde_json::from_str(&line?)?;
+            if event.seq >= until || events.len() == limit {
+                break;
+            }
+            if event.seq >= seq {
+                events.push(event);
+            }
+        }
+        Ok(events)
+    }
+}
+
+/// Log of the execution events, looked up by sequence number. The latest
+/// events are kept in memory up to `max_events` and `max_age_secs`, older
+/// ones are spilled to `spill_file` (or dropped without one) and numbered on
+/// from there across restarts.
+pub struct EventLog {
+    events: VecDeque<Event>,
+    next_seq: u64,
+    max_events: usize,
+    max_age_ms: i64,
+    spill: Option<Spill>,
+    live: broadcast::Sender<Event>,
+}
+
+impl EventLog {
+    /// The spill file keeps the events of earlier runs, the latest ones come
+    /// back from the journal.
+    pub fn new(cfg: &EventLogCfg) -> io::Result<Self> {
+        let spill = match cfg.spill_file.as_str() {
+            "" => None,
+            path => Some(Spill::open(path)?),
+        };
+        Ok(EventLog {
+            events: VecDeque::new(),
+            next_seq: spill.as_ref().map_or(0, |spill| spill.last_seq) + 1,
+            max_events: match cfg.max_events {
+                0 => DEFAULT_MAX_EVENTS,
+                n => n,
+            },
+            max_age_ms: (cfg.max_age_secs * 1000) as i64,
+            spill,
+            live: broadcast::channel(LIVE_BUFFER).0,
+        })
+    }
+
+    /// Events the journal keeps and their maximum age in ms, 0 for no limit:
+    /// those kept in memory, older ones go to the spill file. Without one the
+    /// journal keeps them all.
+    pub fn retention(&self) -> (usize, i64) {
+        match self.spill {
+            Some(_) => (self.max_events, self.max_age_ms),
+            None => (0, 0),
+        }
+    }
+
+    /// Moves events of an earlier run the journal no longer keeps to the
+    /// spill file.
+    pub fn spill(&mut self, events: &[Event]) -> io::Result<()> {
+        let Some(spill) = self.spill.as_mut() else {
+            return Ok(());
+        };
+        for event in events {
+            spill.append(event)?;
+            self.next_seq = self.next_seq.max(event.seq + 1);
+        }
+        Ok(())
+    }
+
+    /// Events of an earlier run, with their sequence numbers and times.
+    pub fn restore(&mut self, events: Vec<Event>) {
+        for event in events {
+            self.next_seq = event.seq + 1;
+            self.events.push_back(event);
+        }
+        self.evict(chrono::Utc::now().timestamp_millis());
+    }
+
+    /// Appends an event, pushes it to the subscribers and returns its
+    /// sequence number.
+    pub fn add_message(&mut self, message: String) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        let event = Event {
+            seq,
+            time_ms: now_ms,
+            message,
+        };
+        // no subscriber is not an error
+        let _ = self.live.send(event.clone());
+        self.events.push_back(event);
+        self.evict(now_ms);
+        seq
+    }
+
+    /// Receiver of the events added from now on. Taken under the same lock
+    /// as a `get_from` catch-up, no event falls in between.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.live.subscribe()
+    }
+
+    /// Up to `limit` events from `seq` on, from the oldest one still kept
+    /// when `seq` is gone.
+    pub fn get_from(&self, seq: u64, limit: usize) -> Vec<Event> {
+        let in_memory = self.events.front().map_or(self.next_seq, |e| e.seq);
+        let mut events = Vec::new();
+        let mut seq = seq;
+        if seq < in_memory {
+            if let Some(spill) = &self.spill {
+                match spill.read(seq, in_memory, limit) {
+                    Ok(spilled) => events = spilled,
+                    Err(e) => error!("read event {} from {} failed: {}", seq, spill.path, e),
+                }
+            }
+            if events.len() == limit {
+                return events;
+            }
+            seq = in_memory;
+        }
+        let skip = ((seq - in_memory) as usize).min(self.events.len());
+        events.extend(
+            self.events
+                .range(skip..)
+                .take(limit - events.len())
+                .cloned(),
+        );
+        events
+    }
+
+    fn evict(&mut self, now_ms: i64) {
+        while let Some(front) = self.events.front() {
+            let old = self.max_age_ms > 0 && now_ms - front.time_ms > self.max_age_ms;
+            if self.events.len() <= self.max_events && !old {
+                break;
+            }
+            let event = self.events.pop_front().unwrap();
+            let Some(spill) = self.spill.as_mut() else {
+                continue;
+            };
+            // a line cut short would break the lookups, older events are dropped from here on
+            if let Err(e) = spill.append(&event) {
+                error!(
+                    "spill event {} to {} failed, spill stopped: {}",
+                    event.seq, spill.path, e
+                );
+                self.spill = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(max_events: usize, spill_file: &str) -> EventLog {
+        EventLog::new(&EventLogCfg {
+            max_events,
+            max_age_secs: 0,
+            spill_file: spill_file.to_string(),
+        })
+        .unwrap()
+    }
+
+    fn seqs(events: &[Event]) -> Vec<u64> {
+        events.iter().map(|e| e.seq).collect()
+    }
+
+    #[test]
+    fn spilled_events_are_read_back_by_offset() {
+        let path = std::env::temp_dir().join(format!("event_log_{}.spill", std::process::id()));
+        let mut log = log(2, path.to_str().unwrap());
+        for i in 1..=5 {
+            assert_eq!(log.add_message(format!("event {i}")), i);
+        }
+        // 1 to 3 spilled, 4 and 5 in memory
+        assert_eq!(seqs(&log.get_from(1, 10)), [1, 2, 3, 4, 5]);
+        assert_eq!(seqs(&log.get_from(2, 2)), [2, 3]);
+        assert_eq!(seqs(&log.get_from(3, 2)), [3, 4]);
+        assert_eq!(seqs(&log.get_from(5, 10)), [5]);
+        assert!(log.get_from(6, 10).is_empty());
+        assert!(log.get_from(1, 0).is_empty());
+        assert_eq!(log.get_from(3, 1)[0].message, "event 3");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn spill_file_is_kept_across_restarts() {
+        let path = std::env::temp_dir().join(format!("event_log_{}.kept", std::process::id()));
+        let path = path.to_str().unwrap();
+        {
+            let mut log = log(2, path);
+            for i in 1..=5 {
+                log.add_message(format!("event {i}"));
+            }
+        }
+        // a crash in the middle of a line
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(b"{\"seq\":4,").unwrap();
+
+        let mut log = log(2, path);
+        assert_eq!(seqs(&log.get_from(1, 10)), [1, 2, 3]);
+        assert_eq!(log.add_message("event 4".to_string()), 4);
+        log.add_message("event 5".to_string());
+        log.add_message("event 6".to_string());
+        assert_eq!(seqs(&log.get_from(2, 10)), [2, 3, 4, 5, 6]);
+        assert_eq!(log.get_from(4, 1)[0].message, "event 4");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn without_spill_file_evicted_events_are_dropped() {
+        let mut log = log(2, "");
+        for i in 1..=5 {
+            log.add_message(format!("event {i}"));
+        }
+        assert_eq!(seqs(&log.get_from(1, 10)), [4, 5]);
+    }
+
+    #[test]
+    fn subscribers_get_the_events_added_after_subscribing() {
+        let mut log = log(10, "");
+        log.add_message("before".to_string());
+        let mut live = log.subscribe();
+        log.add_message("after".to_string());
+        let event = live.try_recv().unwrap();
+        assert_eq!((event.seq, event.message.as_str()), (2, "after"));
+        assert!(live.try_recv().is_err());
+    }
+}
@@ -0,0 +1,147 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use fantasy_fix42::ExecutionReport;
+use fantasy_fix42::field_id;
+use fantasy_fix42::field_types::{ExecTransType, ExecType, OrdStatus, Side};
+use quickfix::{FieldMap, Message};
+
+use crate::fix_convert::gw_plugin::Plugin;
+use crate::instrument::InstrumentStore;
+
+/// Gateway view of an `ExecutionReport`, as pushed to gRPC subscribers.
+#[derive(Debug, Clone)]
+pub struct ExecutionEvent {
+    pub order_id: String,
+    pub cl_ord_id: Option<String>,
+    pub orig_cl_ord_id: Option<String>,
+    /// Replace chain of the order, from the order book.
+    pub chain_id: Option<String>,
+    pub exec_id: String,
+    pub exec_trans_type: Option<ExecTransType>,
+    /// ExecID a cancel or correct refers to.
+    pub exec_ref_id: Option<String>,
+    pub exec_type: ExecType,
+    pub ord_status: OrdStatus,
+    pub symbol: String,
+    pub side: Side,
+    pub leaves_qty: f64,
+    pub cum_qty: f64,
+    pub avg_px: f64,
+    pub last_shares: Option<f64>,
+    pub last_px: Option<f64>,
+    pub text: Option<String>,
+    /// PossDupFlag or PossResend was set, the report may have been seen before.
+    pub poss_dup: bool,
+    /// Custom tags echoed from the report, see `Plugin::echo_tags`.
+    pub custom_tags: BTreeMap<i32, String>,
+}
+
+impl ExecutionEvent {
+    pub fn from_report(report: &ExecutionReport) -> Self {
+        ExecutionEvent {
+            order_id: report.get_order_id(),
+            cl_ord_id: report.get_cl_ord_id(),
+            orig_cl_ord_id: report.get_orig_cl_ord_id(),
+            chain_id: None,
+            exec_id: report.get_exec_id(),
+            exec_trans_type: report.get_exec_trans_type(),
+            exec_ref_id: report.get_exec_ref_id(),
+            exec_type: report.get_exec_type(),
+            ord_status: report.get_ord_status(),
+            symbol: report.get_symbol(),
+            side: report.get_side(),
+            leaves_qty: report.get_leaves_qty(),
+            cum_qty: report.get_cum_qty(),
+            avg_px: report.get_avg_px(),
+            last_shares: report.get_last_shares(),
+            last_px: report.get_last_px(),
+            text: report.get_text(),
+            poss_dup: false,
+            custom_tags: BTreeMap::new(),
+        }
+    }
+
+    /// Builds the event for a received report: the symbol is mapped back to
+    /// ours, PossDupFlag/PossResend are read from the header and the plugin
+    /// echo tags are copied from the raw message.
+    pub fn translate(
+        report: &ExecutionReport,
+        msg: &Message,
+        plugin: &dyn Plugin,
+        instruments: &InstrumentStore,
+    ) -> Self {
+        let mut event = ExecutionEvent::from_report(report);
+        event.symbol = instruments.internal_symbol(&event.symbol).to_string();
+        event.poss_dup = [field_id::POSS_DUP_FLAG, field_id::POSS_RESEND]
+            .into_iter()
+            .any(|tag| msg.with_header(|h| h.get_field(tag)).as_deref() == Some("Y"));
+        for tag in plugin.echo_tags() {
+            if let Some(value) = msg.get_field(tag) {
+                event.custom_tags.insert(tag, value);
+            }
+        }
+        event
+    }
+
+    /// Event fields as `(name, value)` pairs, custom tags are named by number.
+    pub fn fields(&self) -> Vec<(String, String)> {
+        let mut fields = vec![("order_id".to_string(), self.order_id.clone())];
+        if let Some(cl_ord_id) = &self.cl_ord_id {
+            fields.push(("cl_ord_id".to_string(), cl_ord_id.clone()));
+        }
+        if let Some(orig_cl_ord_id) = &self.orig_cl_ord_id {
+            fields.push(("orig_cl_ord_id".to_string(), orig_cl_ord_id.clone()));
+        }
+        if let Some(chain_id) = &self.chain_id {
+            fields.push(("chain_id".to_string(), chain_id.clone()));
+        }
+        fields.extend([("exec_id".to_string(), self.exec_id.clone())]);
+        if let Some(exec_trans_type) = &self.exec_trans_type {
+            fields.push((
+                "exec_trans_type".to_string(),
+                format!("{:?}", exec_trans_type),
+            ));
+        }
+        if let Some(exec_ref_id) = &self.exec_ref_id {
+            fields.push(("exec_ref_id".to_string(), exec_ref_id.clone()));
+        }
+        fields.extend([
+            ("exec_type".to_string(), format!("{:?}", self.exec_type)),
+            ("ord_status".to_string(), format!("{:?}", self.ord_status)),
+            ("symbol".to_string(), self.symbol.clone()),
+            ("side".to_string(), format!("{:?}", self.side)),
+            ("leaves_qty".to_string(), self.leaves_qty.to_string()),
+            ("cum_qty".to_string(), self.cum_qty.to_string()),
+            ("avg_px".to_string(), self.avg_px.to_string()),
+        ]);
+        if let Some(last_shares) = self.last_shares {
+            fields.push(("last_shares".to_string(), last_shares.to_string()));
+        }
+        if let Some(last_px) = self.last_px {
+            fields.push(("last_px".to_string(), last_px.to_string()));
+        }
+        if let Some(text) = &self.text {
+            fields.push(("text".to_string(), text.clone()));
+        }
+        if self.poss_dup {
+            fields.push(("poss_dup".to_string(), "Y".to_string()));
+        }
+        for (tag, value) in &self.custom_tags {
+            fields.push((tag.to_string(), value.clone()));
+        }
+        fields
+    }
+}
+
+impl fmt::Display for ExecutionEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (name, value)) in self.fields().iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{}={}", name, value)?;
+        }
+        Ok(())
+    }
+}
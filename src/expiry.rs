@@ -0,0 +1,49 @@
+use chrono::{DateTime, Duration, NaiveDateTime, NaiveTime, Utc};
+
+use crate::server::fantasy::{RequestMessage, TimeInForce};
+
+/// Format of ExpireTime (FIX UTCTimestamp), in requests and on the wire.
+pub const EXPIRE_TIME_FORMAT: &str = "%Y%m%d-%H:%M:%S";
+
+pub fn parse_expire_time(expire_time: &str) -> Result<DateTime<Utc>, String> {
+    NaiveDateTime::parse_from_str(expire_time, EXPIRE_TIME_FORMAT)
+        .map(|time| time.and_utc())
+        .map_err(|e| format!("expire_time {expire_time}: {e}"))
+}
+
+/// When the gateway cancels the orders whose TimeInForce the broker does not
+/// handle itself: GoodTillDate orders at their ExpireTime, day orders at the
+/// end of the FIX session.
+#[derive(Debug, Clone)]
+pub struct ExpiryPolicy {
+    /// `EndTime` of the session schedule, UTC.
+    session_end: Option<NaiveTime>,
+}
+
+impl ExpiryPolicy {
+    /// `session_end` is the `EndTime` of fix.ini, `HH:MM:SS`.
+    pub fn new(session_end: &str) -> Self {
+        ExpiryPolicy {
+            session_end: NaiveTime::parse_from_str(session_end, "%H:%M:%S").ok(),
+        }
+    }
+
+    /// Next end of the session after `now`.
+    pub fn session_end_after(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let end = now.date_naive().and_time(self.session_end?).and_utc();
+        Some(if end > now {
+            end
+        } else {
+            end + Duration::days(1)
+        })
+    }
+
+    /// When the order of `req` has to be canceled by the gateway.
+    pub fn expire_at(&self, req: &RequestMessage, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match req.time_in_force() {
+            TimeInForce::GoodTillDate => parse_expire_time(&req.expire_time).ok(),
+            TimeInForce::Day => self.session_end_after(now),
+            _ => None,
+        }
+    }
+}
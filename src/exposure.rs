@@ -0,0 +1,167 @@
+use std::collections::BTreeMap;
+
+use crate::algo::ParentBook;
+use crate::order_manager::OrderManager;
+use crate::position::PositionBook;
+use crate::server::fantasy::{AccountExposure, OrdType, RequestMessage};
+
+/// What an account has at risk, in notional.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Exposure {
+    /// Open quantity of the live orders, one per replace chain, and the
+    /// quantity parent orders have still to send, at the order price (mark
+    /// price for market orders).
+    pub open_orders: f64,
+    /// Filled positions at the mark price, average cost while none is set.
+    pub positions: f64,
+}
+
+impl Exposure {
+    pub fn total(&self) -> f64 {
+        self.open_orders + self.positions
+    }
+
+    pub fn report(&self, account: &str, credit_limit: Option<f64>) -> AccountExposure {
+        AccountExposure {
+            account: account.to_string(),
+            open_orders: self.open_orders,
+            positions: self.positions,
+            exposure: self.total(),
+            credit_limit: credit_limit.unwrap_or_default(),
+            available: credit_limit
+                .map(|limit| limit - self.total())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Exposure of every account with live orders, working parent orders or
+/// positions, from the books as they are now: fills move exposure from open
+/// orders to positions, cancels and rejects release it.
+pub fn exposures(
+    parents: &ParentBook,
+    om: &OrderManager,
+    positions: &PositionBook,
+) -> BTreeMap<String, Exposure> {
+    let mut exposures: BTreeMap<String, Exposure> = BTreeMap::new();
+    let price = |price: f64, symbol: &str| {
+        if price > 0.0 {
+            price
+        } else {
+            positions.mark_price(symbol).unwrap_or_default()
+        }
+    };
+    // one order per replace chain: the live one, or while a replace is
+    // pending the larger of it and the replacing order
+    let mut chains: BTreeMap<&str, (&str, f64)> = BTreeMap::new();
+    for order in om.open_orders() {
+        let open = (order.quantity - order.cum_qty).max(0.0) * price(order.price, &order.symbol);
+        let chain = chains
+            .entry(&order.chain_id)
+            .or_insert((&order.account, 0.0));
+        chain.1 = chain.1.max(open);
+    }
+    for (account, open) in chains.into_values() {
+        exposures
+            .entry(account.to_string())
+            .or_default()
+            .open_orders += open;
+    }
+    for (parent, unsent) in parents.unsent(om) {
+        exposures
+            .entry(parent.account.clone())
+            .or_default()
+            .open_orders += unsent * price(parent.price, &parent.symbol);
+    }
+    for position in positions.query("", "") {
+        let price = if position.mark_price > 0.0 {
+            position.mark_price
+        } else {
+            position.avg_cost
+        };
+        exposures.entry(position.account).or_default().positions += position.quantity.abs() * price;
+    }
+    exposures
+}
+
+/// Exposure `req` adds to its account, `None` for a market order without a
+/// mark price. A replace adds its open notional less that of the order it
+/// replaces, so reducing an order is never blocked.
+pub fn added_by(req: &RequestMessage, om: &OrderManager, positions: &PositionBook) -> Option<f64> {
+    let price = match req.ord_type() {
+        OrdType::Market => positions.mark_price(&req.symbol)?,
+        _ => req.price,
+    };
+    let orig = (!req.orig_cl_ord_id.is_empty())
+        .then(|| om.get_chain(&req.client_id, &req.orig_cl_ord_id))
+        .flatten()
+        .and_then(|(chain, live)| chain.into_iter().find(|o| o.cl_ord_id == live));
+    let Some(orig) = orig else {
+        return Some(req.quantity * price);
+    };
+    let orig_price = if orig.price > 0.0 { orig.price } else { price };
+    let before = (orig.quantity - orig.cum_qty).max(0.0) * orig_price;
+    let after = (req.quantity - orig.cum_qty).max(0.0) * price;
+    Some(after - before)
+}
+
+#[cfg(test)]
+mod tests {
+    use fantasy_fix42::field_types::{ExecType, OrdStatus};
+
+    use super::*;
+    use crate::order_manager::tests::{book, order, report};
+
+    fn replace(orig_cl_ord_id: &str, quantity: f64, price: f64) -> RequestMessage {
+        RequestMessage {
+            message: "r2".to_string(),
+            orig_cl_ord_id: orig_cl_ord_id.to_string(),
+            client_id: "c1".to_string(),
+            symbol: "USDJPY".to_string(),
+            ord_type: OrdType::Limit as i32,
+            quantity,
+            price,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn replace_adds_its_notional_less_that_of_the_order_it_replaces() {
+        let mut om = book();
+        let positions = PositionBook::new();
+        let id = order(&mut om, "r1", 1000.0);
+        om.apply_execution(&report(&id, "e1", ExecType::New, OrdStatus::New, 0.0))
+            .unwrap();
+
+        let added = |req: &RequestMessage| added_by(req, &om, &positions).unwrap();
+        assert_eq!(added(&replace(&id, 1500.0, 150.0)), 500.0 * 150.0);
+        assert_eq!(added(&replace(&id, 1000.0, 151.0)), 1000.0);
+        // reducing is negative, never blocked by the credit limit
+        assert_eq!(added(&replace(&id, 400.0, 150.0)), -600.0 * 150.0);
+        // a new order adds all of its notional
+        assert_eq!(added(&replace("", 1000.0, 150.0)), 1000.0 * 150.0);
+
+        // the filled part is in positions, not in the open orders
+        om.apply_execution(&report(
+            &id,
+            "e2",
+            ExecType::PartialFill,
+            OrdStatus::PartiallyFilled,
+            800.0,
+        ))
+        .unwrap();
+        let added = |req: &RequestMessage| added_by(req, &om, &positions).unwrap();
+        assert_eq!(added(&replace(&id, 1000.0, 151.0)), 200.0);
+    }
+
+    #[test]
+    fn market_order_is_valued_at_the_mark_price() {
+        let om = book();
+        let mut positions = PositionBook::new();
+        let mut req = replace("", 1000.0, 0.0);
+        req.ord_type = OrdType::Market as i32;
+        assert_eq!(added_by(&req, &om, &positions), None);
+        positions.set_mark("USDJPY", 150.0);
+        assert_eq!(added_by(&req, &om, &positions), Some(150_000.0));
+    }
+}
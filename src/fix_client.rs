@@ -1,4 +1,4 @@
-use log::{error, info};
+use log::{error, info, warn};
 use std::fmt;
 use std::io;
 use std::io::Write;
@@ -6,11 +6,18 @@ use std::sync::Arc;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use crate::cfg::BrokerName;
+use crate::cfg::{BrokerName, ThrottleBreach};
+use crate::execution::ExecutionEvent;
+use crate::expiry::ExpiryPolicy;
 use crate::fix_convert::broker::Broker;
 use crate::fix_convert::gw_plugin::Plugin;
+use crate::fix_convert::reload::ReloadablePlugin;
+use crate::instrument::InstrumentStore;
+use crate::order_manager::{Execution, FillUpdate, log_anomaly};
+use crate::store::Record;
+use crate::symbol_lists::Listed;
 
 use fantasy_fix42::Messages;
 use fantasy_fix42::NewOrderSingle;
@@ -25,8 +32,9 @@ use tokio::sync::mpsc;
 use tokio::time::sleep;
 
 use crate::GwConfig;
+use crate::event_log::EventLog;
 use crate::server::fantasy::RequestMessage;
-use crate::shared_data::SharedData;
+use crate::shared_data::Books;
 
 #[derive(Debug, PartialEq)]
 pub enum QuickFixState {
@@ -38,40 +46,43 @@ pub enum QuickFixState {
 #[derive(Debug)]
 pub enum ForwardRequest {
     RequestMessage(RequestMessage),
+    CancelRequest(RequestMessage),
+    ReplaceRequest(RequestMessage),
     ErrorMessage(String),
 }
 
 pub struct FixApplication {
-    shared_data: Arc<Mutex<SharedData>>,
-    handle: Handle,
+    events: Arc<Mutex<EventLog>>,
     gw_config: GwConfig,
     connected: Arc<AtomicBool>,
     plugin: Arc<dyn Plugin>,
+    instruments: Arc<InstrumentStore>,
+    books: Books,
 }
 
 impl FixApplication {
     pub fn new(
-        shared_data: Arc<Mutex<SharedData>>,
-        handle: Handle,
+        events: Arc<Mutex<EventLog>>,
         gw_config: GwConfig,
         connected: Arc<AtomicBool>,
         plugin: Arc<dyn Plugin>,
+        instruments: Arc<InstrumentStore>,
+        books: Books,
     ) -> FixApplication {
         FixApplication {
-            shared_data,
-            handle,
+            events,
             gw_config,
             connected,
             plugin,
+            instruments,
+            books,
         }
     }
 
+    /// Logs the event in the FIX callback itself, so subscribers get it
+    /// right away and in the order the messages came.
     pub fn update_cache(&self, message: String) {
-        let shared_data_clone = Arc::clone(&self.shared_data);
-        self.handle.spawn(async move {
-            let mut data = shared_data_clone.lock().await;
-            data.add_message(message);
-        });
+        self.events.blocking_lock().add_message(message);
     }
 }
 
@@ -117,9 +128,45 @@ impl ApplicationCallback for FixApplication {
     fn on_msg_from_app(&self, msg: &Message, _session: &SessionId) -> Result<(), MsgFromAppError> {
         match Messages::decode(msg.clone()) {
             Ok(Messages::ExecutionReport(x)) => {
-                let order_id: String = x.get_order_id();
-                info!("- Order ID:           {order_id}");
-                self.update_cache(order_id);
+                let mut event =
+                    ExecutionEvent::translate(&x, msg, self.plugin.as_ref(), &self.instruments);
+                info!("- Order ID:           {}", event.order_id);
+                let execution = {
+                    let mut om = self.books.orders.blocking_lock();
+                    let execution = log_anomaly(om.apply_execution(&event));
+                    event.chain_id = om.chain_id(&event);
+                    // positions follow under the order book lock, so exposure never misses a fill
+                    if let Some(Execution::Applied(Some(update))) = &execution {
+                        self.books.record(&match update {
+                            FillUpdate::Fill(fill) => Record::Fill(fill.clone()),
+                            FillUpdate::Bust { exec_id, .. } => Record::Bust {
+                                exec_id: exec_id.clone(),
+                            },
+                        });
+                        self.books.positions.blocking_lock().apply(update);
+                    }
+                    execution
+                };
+                if let Some(Execution::Duplicate) = execution {
+                    info!("- Duplicate ExecID:   {} dropped", event.exec_id);
+                    return Ok(());
+                }
+                let message = event.to_string();
+                self.books.record(&Record::event(message.clone()));
+                self.update_cache(message);
+            }
+            Ok(Messages::OrderCancelReject(x)) => {
+                let cl_ord_id = x.get_cl_ord_id();
+                info!(
+                    "- Cancel rejected:    {cl_ord_id} (order {})",
+                    x.get_orig_cl_ord_id()
+                );
+                log_anomaly(
+                    self.books
+                        .orders
+                        .blocking_lock()
+                        .cancel_rejected(&cl_ord_id),
+                );
             }
             Ok(msg) => info!("{msg:?}"),
             Err(err) => error!("Cannot decode message: {err:?}"),
@@ -170,12 +217,153 @@ impl LogCallback for FantasyLogger {
     }
 }
 
+fn send_converted(converted: Result<Message, QuickFixError>, session_id: &SessionId) -> bool {
+    match converted {
+        Ok(msg) => match send_to_target(msg, session_id) {
+            Ok(()) => true,
+            Err(e) => {
+                error!("Failed to send order: {}", e);
+                false
+            }
+        },
+        Err(e) => {
+            error!("Failed to convert order: {}", e);
+            false
+        }
+    }
+}
+
+/// How often orders are checked for their local expiry.
+const EXPIRY_CHECK: Duration = Duration::from_secs(1);
+
+/// Schedules the cancel of a sent order whose TimeInForce the broker does not
+/// handle itself.
+async fn schedule_expiry(
+    books: &Books,
+    plugin: &dyn Plugin,
+    expiry: &ExpiryPolicy,
+    req: &RequestMessage,
+) {
+    if plugin.expires_natively(req.time_in_force()) {
+        return;
+    }
+    if let Some(at) = expiry.expire_at(req, chrono::Utc::now()) {
+        info!("order {} expires locally at {}", req.message, at);
+        log_anomaly(books.orders.lock().await.schedule_expiry(&req.message, at));
+    }
+}
+
+/// Sends a cancel for every order past its local expiry.
+async fn expire_orders(books: &Books, plugin: &dyn Plugin, session_id: &SessionId) {
+    let mut cancels = Vec::new();
+    {
+        let mut om = books.orders.lock().await;
+        for mut req in om.take_expired(chrono::Utc::now()) {
+            info!("order {} expired, canceling", req.orig_cl_ord_id);
+            if log_anomaly(om.add_cancel(&mut req)).is_some() {
+                cancels.push(req);
+            }
+        }
+    }
+    for req in cancels {
+        throttle(books, false, 0).await;
+        if !send_converted(plugin.convert_to_order_cancel_request(&req), session_id) {
+            log_anomaly(books.orders.lock().await.cancel_rejected(&req.message));
+        }
+    }
+}
+
+/// Waits until the throttle lets a message out, `queued` requests are behind
+/// it. False when the message is an `order` (new or replace) the throttle
+/// rejects instead.
+async fn throttle(books: &Books, order: bool, queued: usize) -> bool {
+    loop {
+        let delay = {
+            let mut throttle = books.throttle.lock().await;
+            throttle.set_queued(queued);
+            let now = Instant::now();
+            if throttle.try_acquire(now) {
+                return true;
+            }
+            if order && throttle.on_breach() == ThrottleBreach::RejectOrders {
+                throttle.count_rejected();
+                return false;
+            }
+            throttle.delay(now)
+        };
+        sleep(delay).await;
+    }
+}
+
+/// Why a new order or replace accepted earlier may no longer be sent: a
+/// trading halt or a restricted symbol that came in since.
+async fn blocked(books: &Books, req: &RequestMessage) -> Option<String> {
+    if let Some(halt) = books.kill_switch.lock().await.halted(&req.account) {
+        return Some(format!(
+            "trading halted by {}: {}",
+            halt.operator, halt.reason
+        ));
+    }
+    match books
+        .symbol_lists
+        .lock()
+        .await
+        .check(&req.account, &req.symbol)
+    {
+        Some(Listed::Restricted(group)) => Some(format!(
+            "{} restricted for account {} (group {})",
+            req.symbol, req.account, group
+        )),
+        _ => None,
+    }
+}
+
+/// Rejects a new order or replace that is not sent, the reason is journaled
+/// and pushed to `ServerStream`.
+async fn reject_unsent(books: &Books, events: &Mutex<EventLog>, req: &RequestMessage, why: &str) {
+    let message = format!("order {} rejected: {}", req.message, why);
+    warn!("{}", message);
+    log_anomaly(books.orders.lock().await.reject(&req.message));
+    books.record(&Record::event(message.clone()));
+    events.lock().await.add_message(message);
+}
+
+/// Creates the plugin of the configured broker, reloadable from `plugin_cfg_file`.
+pub fn create_plugin(
+    gw_config: &GwConfig,
+    instruments: Arc<InstrumentStore>,
+) -> Result<Arc<ReloadablePlugin>, QuickFixError> {
+    let broker_name = gw_config.broker_name.clone();
+    let plugin_cfg_file = gw_config.plugin_cfg_file.clone();
+    let plugin = ReloadablePlugin::try_new(
+        &gw_config.plugin_cfg_file,
+        Box::new(move || -> Result<Arc<dyn Plugin>, QuickFixError> {
+            match &broker_name {
+                BrokerName::Broker1 => {
+                    info!("===============Broker1==================");
+                    Ok(Arc::new(Broker::try_new(
+                        &plugin_cfg_file,
+                        instruments.clone(),
+                    )?))
+                }
+                BrokerName::Broker2 => Ok(Arc::new(Broker::try_new(
+                    &plugin_cfg_file,
+                    instruments.clone(),
+                )?)),
+            }
+        }),
+    )?;
+    Ok(Arc::new(plugin))
+}
+
 pub fn start_quickfix_server(
-    config_file: String,
     order_recv: &mut mpsc::UnboundedReceiver<ForwardRequest>,
-    shared_data: Arc<tokio::sync::Mutex<SharedData>>,
+    events: Arc<tokio::sync::Mutex<EventLog>>,
     handle: Handle,
     gw_config: GwConfig,
+    plugin: Arc<ReloadablePlugin>,
+    instruments: Arc<InstrumentStore>,
+    books: Books,
 ) -> Result<(), QuickFixError> {
     /*
         let mut my_string = String::from("");
@@ -184,28 +372,26 @@ pub fn start_quickfix_server(
         });
         println!("--------------------{:?}", my_string);
     */
-    let settings = SessionSettings::try_from_path(config_file)?;
+    let settings = SessionSettings::try_from_path(&gw_config.fix_cfg)?;
     let store_factory = FileMessageStoreFactory::try_new(&settings)?;
     let log_factory = LogFactory::try_new(&FantasyLogger::Stdout)?;
     let connected = Arc::new(AtomicBool::new(false));
 
-    let plugin: Arc<dyn Plugin>;
-    match &gw_config.broker_name {
-        BrokerName::Broker1 => {
-            info!("===============Broker1==================");
-            plugin = Arc::new(Broker::new(&gw_config.plugin_cfg_file));
-        }
-        BrokerName::Broker2 => {
-            plugin = Arc::new(Broker::new(&gw_config.plugin_cfg_file));
-        }
+    if !gw_config.plugin_cfg_file.is_empty() && gw_config.plugin_reload_interval > 0 {
+        handle.spawn(
+            plugin
+                .clone()
+                .watch(Duration::from_millis(gw_config.plugin_reload_interval)),
+        );
     }
 
     let fix_application = FixApplication::new(
-        shared_data,
-        handle.clone(),
+        events.clone(),
         gw_config.clone(),
         connected.clone(),
         plugin.clone(),
+        instruments,
+        books.clone(),
     );
 
     let app = Application::try_new(&fix_application)?;
@@ -224,9 +410,21 @@ pub fn start_quickfix_server(
         acceptor.stop()?;
         return Err(QuickFixError::invalid_argument("create session_id error"));
     };
+    let expiry = ExpiryPolicy::new(
+        &settings
+            .with_dictionary(Some(&session_id), |dict| {
+                dict.get::<String>("EndTime").unwrap_or_default()
+            })
+            .unwrap_or_default(),
+    );
 
     handle.block_on(async move {
+        let mut next_expiry_check = Instant::now();
         loop {
+            if connected.load(Ordering::Relaxed) && Instant::now() >= next_expiry_check {
+                next_expiry_check = Instant::now() + EXPIRY_CHECK;
+                expire_orders(&books, plugin.as_ref(), &session_id).await;
+            }
             if order_recv.len() == 0 || !connected.load(Ordering::Relaxed) {
                 sleep(Duration::from_millis(200)).await;
                 continue;
@@ -234,14 +432,54 @@ pub fn start_quickfix_server(
             let Some(request) = order_recv.recv().await else {
                 continue;
             };
+            let order = matches!(
+                request,
+                ForwardRequest::RequestMessage(_) | ForwardRequest::ReplaceRequest(_)
+            );
+            // halts and restrictions since the request was accepted apply too
+            if let ForwardRequest::RequestMessage(req) | ForwardRequest::ReplaceRequest(req) =
+                &request
+            {
+                let blocked = blocked(&books, req).await;
+                if let Some(why) = blocked {
+                    reject_unsent(&books, &events, req, &why).await;
+                    continue;
+                }
+            }
+            let sends = !matches!(request, ForwardRequest::ErrorMessage(_));
+            if sends && !throttle(&books, order, order_recv.len()).await {
+                if let ForwardRequest::RequestMessage(req) | ForwardRequest::ReplaceRequest(req) =
+                    &request
+                {
+                    let why = "outbound message rate of the FIX session exceeded";
+                    reject_unsent(&books, &events, req, why).await;
+                }
+                continue;
+            }
             match request {
                 ForwardRequest::RequestMessage(req) => {
                     println!("Received RequestMessage: {}", req.message);
-                    if let Ok(order) = plugin.convert_to_new_order_single(&req) {
-                        if let Err(e) = send_to_target(order.into(), &session_id) {
-                            error!("Failed to send order: {}", e);
-                            continue;
-                        }
+                    if !send_converted(plugin.convert_to_new_order_single(&req), &session_id) {
+                        log_anomaly(books.orders.lock().await.reject(&req.message));
+                    } else {
+                        schedule_expiry(&books, plugin.as_ref(), &expiry, &req).await;
+                    }
+                }
+                ForwardRequest::CancelRequest(req) => {
+                    info!("Received CancelRequest: {}", req.message);
+                    if !send_converted(plugin.convert_to_order_cancel_request(&req), &session_id) {
+                        log_anomaly(books.orders.lock().await.cancel_rejected(&req.message));
+                    }
+                }
+                ForwardRequest::ReplaceRequest(req) => {
+                    info!("Received ReplaceRequest: {}", req.message);
+                    if !send_converted(
+                        plugin.convert_to_order_cancel_replace_request(&req),
+                        &session_id,
+                    ) {
+                        log_anomaly(books.orders.lock().await.reject(&req.message));
+                    } else {
+                        schedule_expiry(&books, plugin.as_ref(), &expiry, &req).await;
                     }
                 }
                 ForwardRequest::ErrorMessage(err) => {
@@ -1,91 +1,359 @@
-use fantasy_fix42::NewOrderSingle;
-use fantasy_fix42::field_types::{HandlInst, OrdType, Side};
+use std::sync::Arc;
+
+use fantasy_fix42::field_id;
+use fantasy_fix42::field_types::{HandlInst, OrdType, Side, TimeInForce};
+use fantasy_fix42::{NewOrderSingle, OrderCancelReplaceRequest, OrderCancelRequest};
+use log::debug;
 use quickfix::*;
-use serde::de::value::Error;
+use serde::Deserialize;
 
+use crate::expiry::{EXPIRE_TIME_FORMAT, parse_expire_time};
+use crate::fix_convert::dictionary::FieldDictionary;
 use crate::fix_convert::gw_plugin::Plugin;
-use crate::server::fantasy::RequestMessage;
+use crate::fix_convert::mapping::{MappingRules, MessageKind};
+use crate::instrument::InstrumentStore;
+use crate::server::fantasy::{self, RequestMessage};
+
+macro_rules! try_set {
+    ($order:expr, $method:ident, $value:expr, $message:expr) => {
+        if let Err(e) = $order.$method($value) {
+            eprintln!($message, e);
+            return Err(e);
+        }
+    };
+}
+
+/// First tag of the user-defined range, lower tags cannot be passed through.
+const FIRST_USER_DEFINED_TAG: i32 = 5000;
 
+#[derive(Debug, Deserialize, Default)]
+struct CustomTagsCfg {
+    /// Data dictionary client tags are validated against, empty to only use `allowed`.
+    #[serde(default)]
+    pub dictionary: String,
+    #[serde(default)]
+    pub allowed: Vec<i32>,
+    #[serde(default)]
+    pub echo: Vec<i32>,
+}
+
+/// What the broker supports natively, the gateway takes over the rest.
+#[derive(Debug, Deserialize, Default)]
+struct CapabilitiesCfg {
+    /// The broker expires day orders at the end of the session.
+    #[serde(default)]
+    pub day_expiry: bool,
+    /// The broker accepts TimeInForce GoodTillDate with ExpireTime.
+    #[serde(default)]
+    pub good_till_date: bool,
+}
+
+#[derive(Debug, Deserialize, Default)]
 struct BrokerCfg {
-    pub path: String,
+    #[serde(default)]
+    pub mapping_rules: MappingRules,
+    #[serde(default)]
+    pub custom_tags: CustomTagsCfg,
+    #[serde(default)]
+    pub capabilities: CapabilitiesCfg,
 }
 
 pub struct Broker {
     pub plugin_cfg_file: String,
-    pub broker_cfg: BrokerCfg,
+    broker_cfg: BrokerCfg,
+    instruments: Arc<InstrumentStore>,
+    dictionary: FieldDictionary,
 }
 
 impl Broker {
-    pub fn new(plugin_cfg_file: &str) -> Self {
-        // Load custom file here
-        Broker {
+    pub fn try_new(
+        plugin_cfg_file: &str,
+        instruments: Arc<InstrumentStore>,
+    ) -> Result<Self, QuickFixError> {
+        let broker_cfg = if plugin_cfg_file.is_empty() {
+            BrokerCfg::default()
+        } else {
+            let content = std::fs::read_to_string(plugin_cfg_file).map_err(|e| {
+                QuickFixError::invalid_argument(format!("read {plugin_cfg_file}: {e}"))
+            })?;
+            serde_yaml::from_str(&content).map_err(|e| {
+                QuickFixError::invalid_argument(format!("parse {plugin_cfg_file}: {e}"))
+            })?
+        };
+        let dictionary = if broker_cfg.custom_tags.dictionary.is_empty() {
+            FieldDictionary::default()
+        } else {
+            FieldDictionary::load(&broker_cfg.custom_tags.dictionary)?
+        };
+        broker_cfg
+            .mapping_rules
+            .validate(&dictionary, &broker_cfg.custom_tags.allowed)
+            .map_err(|e| QuickFixError::invalid_argument(format!("{plugin_cfg_file}: {e}")))?;
+        Ok(Broker {
             plugin_cfg_file: plugin_cfg_file.to_string(),
-            broker_cfg: BrokerCfg {
-                path: plugin_cfg_file.to_string(),
-            },
+            broker_cfg,
+            instruments,
+            dictionary,
+        })
+    }
+
+    /// Appends the client supplied custom tags, checked as in `check_extra_tags`.
+    fn apply_extra_tags(
+        &self,
+        req: &RequestMessage,
+        msg: &mut Message,
+    ) -> Result<(), QuickFixError> {
+        self.check_extra_tags(req)
+            .map_err(QuickFixError::invalid_argument)?;
+        for (tag, value) in &req.extra_tags {
+            msg.set_field(*tag, value.as_str())?;
+        }
+        Ok(())
+    }
+
+    /// Sets TimeInForce and ExpireTime. A GoodTillDate order the broker does
+    /// not support goes out GoodTillCancel, the gateway cancels it at expiry.
+    fn apply_time_in_force(
+        &self,
+        req: &RequestMessage,
+        msg: &mut Message,
+    ) -> Result<(), QuickFixError> {
+        let time_in_force = match req.time_in_force() {
+            fantasy::TimeInForce::Unspecified => return Ok(()),
+            fantasy::TimeInForce::Day => TimeInForce::Day,
+            fantasy::TimeInForce::GoodTillCancel => TimeInForce::GoodTillCancel,
+            fantasy::TimeInForce::ImmediateOrCancel => TimeInForce::ImmediateOrCancel,
+            fantasy::TimeInForce::FillOrKill => TimeInForce::FillOrKill,
+            fantasy::TimeInForce::GoodTillDate => {
+                let expire_time =
+                    parse_expire_time(&req.expire_time).map_err(QuickFixError::invalid_argument)?;
+                if !self.broker_cfg.capabilities.good_till_date {
+                    TimeInForce::GoodTillCancel
+                } else {
+                    msg.set_field(
+                        field_id::EXPIRE_TIME,
+                        expire_time.format(EXPIRE_TIME_FORMAT).to_string(),
+                    )?;
+                    TimeInForce::GoodTillDate
+                }
+            }
+        };
+        msg.set_field(field_id::TIME_IN_FORCE, time_in_force.as_fix_str())?;
+        Ok(())
+    }
+
+    /// Rewrites the instrument fields of `msg` with the broker symbology and
+    /// checks tick and lot size. Unknown symbols are sent unchanged.
+    fn apply_instrument(
+        &self,
+        kind: MessageKind,
+        req: &RequestMessage,
+        msg: &mut Message,
+    ) -> Result<(), QuickFixError> {
+        let Some(instrument) = self.instruments.get(&req.symbol) else {
+            return Ok(());
+        };
+        if kind != MessageKind::OrderCancelRequest {
+            let price = (req.ord_type() != fantasy::OrdType::Market).then_some(req.price);
+            instrument
+                .validate(price, req.quantity)
+                .map_err(QuickFixError::invalid_argument)?;
+        }
+
+        msg.set_field(field_id::SYMBOL, instrument.broker_symbol.as_str())?;
+        if let Some(security_id) = &instrument.security_id {
+            msg.set_field(field_id::SECURITY_ID, security_id.as_str())?;
+        }
+        if let Some(id_source) = &instrument.id_source {
+            msg.set_field(field_id::ID_SOURCE, id_source.as_str())?;
+        }
+        if let Some(exchange) = &instrument.security_exchange {
+            msg.set_field(field_id::SECURITY_EXCHANGE, exchange.as_str())?;
         }
+        if let Some(currency) = &instrument.currency {
+            // OrderCancelRequest has no Currency field in FIX 4.2
+            if kind != MessageKind::OrderCancelRequest {
+                msg.set_field(field_id::CURRENCY, currency.as_str())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn transact_time() -> String {
+    chrono::Utc::now().format("%Y%m%d-%H:%M:%S%.3f").to_string()
+}
+
+fn to_side(req: &RequestMessage) -> Result<Side, QuickFixError> {
+    match req.side() {
+        fantasy::Side::Buy => Ok(Side::Buy),
+        fantasy::Side::Sell => Ok(Side::Sell),
+        fantasy::Side::Unspecified => Err(QuickFixError::invalid_argument("side is required")),
+    }
+}
+
+fn to_ord_type(req: &RequestMessage) -> OrdType {
+    match req.ord_type() {
+        fantasy::OrdType::Market => OrdType::Market,
+        fantasy::OrdType::Limit | fantasy::OrdType::Unspecified => OrdType::Limit,
     }
 }
 
 impl Plugin for Broker {
+    /// Each tag must be a user-defined tag known to the dictionary or listed
+    /// in `custom_tags.allowed`.
+    fn check_extra_tags(&self, req: &RequestMessage) -> Result<(), String> {
+        for tag in req.extra_tags.keys() {
+            if *tag < FIRST_USER_DEFINED_TAG {
+                return Err(format!("tag {tag} is not a user-defined tag"));
+            }
+            if !self.dictionary.contains(*tag) && !self.broker_cfg.custom_tags.allowed.contains(tag)
+            {
+                return Err(format!("tag {tag} is not in the dictionary or allow-list"));
+            }
+        }
+        Ok(())
+    }
+
+    fn echo_tags(&self) -> Vec<i32> {
+        self.broker_cfg.custom_tags.echo.clone()
+    }
+
+    fn expires_natively(&self, time_in_force: fantasy::TimeInForce) -> bool {
+        let capabilities = &self.broker_cfg.capabilities;
+        match time_in_force {
+            fantasy::TimeInForce::Day => capabilities.day_expiry,
+            fantasy::TimeInForce::GoodTillDate => capabilities.good_till_date,
+            _ => true,
+        }
+    }
+
     fn convert_to_new_order_single(
         &self,
         req: &RequestMessage,
-    ) -> Result<NewOrderSingle, quickfix::QuickFixError> {
-        println!("[{}] convert_to_new_order_single", self.plugin_cfg_file);
-        let now = chrono::Utc::now();
-        let timestamp = now.format("%Y%m%d-%H:%M:%S%.3f").to_string();
-
+    ) -> Result<Message, quickfix::QuickFixError> {
+        debug!("[{}] convert_to_new_order_single", self.plugin_cfg_file);
         let mut order = NewOrderSingle::try_new(
             req.message.clone(),
             HandlInst::AutomatedExecutionNoIntervention,
-            "USDJPY".to_string(),
-            Side::Buy,
-            timestamp,
-            OrdType::Limit,
+            req.symbol.clone(),
+            to_side(req)?,
+            transact_time(),
+            to_ord_type(req),
         )?;
 
-        macro_rules! try_set {
-            ($order:expr, $method:ident, $value:expr, $message:expr) => {
-                if let Err(e) = $order.$method($value) {
-                    eprintln!($message, e);
-                    return Err(e);
-                }
-            };
-        }
         try_set!(
             order,
             set_order_qty,
-            14.0,
+            req.quantity,
             "Failed to set order quantity: {}"
         );
-        try_set!(
-            order,
-            set_symbol,
-            "USDJPY".to_string(),
-            "Failed to set symbol: {}"
-        );
-        try_set!(order, set_price, 893.123, "Failed to set price: {}");
-        try_set!(
-            order,
-            set_account,
-            "fantasy".to_string(),
-            "Failed to set account: {}"
-        );
-        Ok(order)
+        if req.ord_type() != fantasy::OrdType::Market {
+            try_set!(order, set_price, req.price, "Failed to set price: {}");
+        }
+        if !req.account.is_empty() {
+            try_set!(
+                order,
+                set_account,
+                req.account.clone(),
+                "Failed to set account: {}"
+            );
+        }
+
+        let mut msg: Message = order.into();
+        self.apply_time_in_force(req, &mut msg)?;
+        self.apply_instrument(MessageKind::NewOrderSingle, req, &mut msg)?;
+        self.apply_extra_tags(req, &mut msg)?;
+        self.broker_cfg
+            .mapping_rules
+            .apply(MessageKind::NewOrderSingle, req, &mut msg)?;
+        Ok(msg)
     }
 
     fn convert_to_order_cancel_replace_request(
         &self,
         req: &RequestMessage,
-    ) -> Result<NewOrderSingle, quickfix::QuickFixError> {
-        return Err(QuickFixError::InvalidArgument("ssss".to_string()));
+    ) -> Result<Message, quickfix::QuickFixError> {
+        debug!(
+            "[{}] convert_to_order_cancel_replace_request",
+            self.plugin_cfg_file
+        );
+        let mut order = OrderCancelReplaceRequest::try_new(
+            req.orig_cl_ord_id.clone(),
+            req.message.clone(),
+            HandlInst::AutomatedExecutionNoIntervention,
+            req.symbol.clone(),
+            to_side(req)?,
+            transact_time(),
+            to_ord_type(req),
+        )?;
+
+        try_set!(
+            order,
+            set_order_qty,
+            req.quantity,
+            "Failed to set order quantity: {}"
+        );
+        if req.ord_type() != fantasy::OrdType::Market {
+            try_set!(order, set_price, req.price, "Failed to set price: {}");
+        }
+        if !req.account.is_empty() {
+            try_set!(
+                order,
+                set_account,
+                req.account.clone(),
+                "Failed to set account: {}"
+            );
+        }
+
+        let mut msg: Message = order.into();
+        self.apply_time_in_force(req, &mut msg)?;
+        self.apply_instrument(MessageKind::OrderCancelReplaceRequest, req, &mut msg)?;
+        self.apply_extra_tags(req, &mut msg)?;
+        self.broker_cfg.mapping_rules.apply(
+            MessageKind::OrderCancelReplaceRequest,
+            req,
+            &mut msg,
+        )?;
+        Ok(msg)
     }
 
     fn convert_to_order_cancel_request(
         &self,
         req: &RequestMessage,
-    ) -> Result<NewOrderSingle, quickfix::QuickFixError> {
-        return Err(QuickFixError::InvalidArgument("ssss".to_string()));
+    ) -> Result<Message, quickfix::QuickFixError> {
+        debug!("[{}] convert_to_order_cancel_request", self.plugin_cfg_file);
+        let mut order = OrderCancelRequest::try_new(
+            req.orig_cl_ord_id.clone(),
+            req.message.clone(),
+            req.symbol.clone(),
+            to_side(req)?,
+            transact_time(),
+        )?;
+
+        if req.quantity > 0.0 {
+            try_set!(
+                order,
+                set_order_qty,
+                req.quantity,
+                "Failed to set order quantity: {}"
+            );
+        }
+        if !req.account.is_empty() {
+            try_set!(
+                order,
+                set_account,
+                req.account.clone(),
+                "Failed to set account: {}"
+            );
+        }
+
+        let mut msg: Message = order.into();
+        self.apply_instrument(MessageKind::OrderCancelRequest, req, &mut msg)?;
+        self.apply_extra_tags(req, &mut msg)?;
+        self.broker_cfg
+            .mapping_rules
+            .apply(MessageKind::OrderCancelRequest, req, &mut msg)?;
+        Ok(msg)
     }
 }
@@ -0,0 +1,345 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use fantasy_fix42::Messages;
+use quickfix::{FieldMap, Message, QuickFixError};
+use serde::Deserialize;
+
+use crate::execution::ExecutionEvent;
+use crate::fix_convert::dictionary::FieldDictionary;
+use crate::fix_convert::gw_plugin::Plugin;
+use crate::fix_convert::mapping::MessageKind;
+use crate::instrument::InstrumentStore;
+use crate::server::fantasy::{self, RequestMessage};
+
+const MSG_TYPE: i32 = 35;
+
+/// Order request of a conformance case, mirrors `RequestMessage`.
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct RequestSpec {
+    pub cl_ord_id: String,
+    pub orig_cl_ord_id: String,
+    pub account: String,
+    pub symbol: String,
+    /// `Buy` or `Sell`
+    pub side: String,
+    /// `Market` or `Limit`
+    pub ord_type: String,
+    pub price: f64,
+    pub quantity: f64,
+    pub extra_tags: HashMap<i32, String>,
+    /// `Day`, `GoodTillCancel`, `ImmediateOrCancel`, `FillOrKill` or `GoodTillDate`
+    pub time_in_force: String,
+    pub expire_time: String,
+}
+
+impl RequestSpec {
+    fn to_request(&self) -> RequestMessage {
+        let side = match self.side.to_ascii_lowercase().as_str() {
+            "buy" => fantasy::Side::Buy,
+            "sell" => fantasy::Side::Sell,
+            _ => fantasy::Side::Unspecified,
+        };
+        let ord_type = match self.ord_type.to_ascii_lowercase().as_str() {
+            "market" => fantasy::OrdType::Market,
+            "limit" => fantasy::OrdType::Limit,
+            _ => fantasy::OrdType::Unspecified,
+        };
+        let time_in_force = match self.time_in_force.to_ascii_lowercase().as_str() {
+            "day" => fantasy::TimeInForce::Day,
+            "goodtillcancel" => fantasy::TimeInForce::GoodTillCancel,
+            "immediateorcancel" => fantasy::TimeInForce::ImmediateOrCancel,
+            "fillorkill" => fantasy::TimeInForce::FillOrKill,
+            "goodtilldate" => fantasy::TimeInForce::GoodTillDate,
+            _ => fantasy::TimeInForce::Unspecified,
+        };
+        RequestMessage {
+            message: self.cl_ord_id.clone(),
+            account: self.account.clone(),
+            symbol: self.symbol.clone(),
+            side: side as i32,
+            price: self.price,
+            quantity: self.quantity,
+            orig_cl_ord_id: self.orig_cl_ord_id.clone(),
+            ord_type: ord_type as i32,
+            extra_tags: self.extra_tags.clone(),
+            time_in_force: time_in_force as i32,
+            expire_time: self.expire_time.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Outgoing case: a request and the FIX fields the plugin must produce.
+///
+/// Fields are keyed by dictionary name or tag number, `absent` lists fields
+/// that must not be on the message and `expect_error` a request the plugin
+/// has to refuse.
+#[derive(Debug, Deserialize)]
+pub struct OrderCase {
+    pub name: String,
+    pub kind: MessageKind,
+    pub request: RequestSpec,
+    #[serde(default)]
+    pub expect: BTreeMap<String, String>,
+    #[serde(default)]
+    pub absent: Vec<String>,
+    #[serde(default)]
+    pub expect_error: bool,
+}
+
+/// Inbound case: an execution report and the gateway event it must become.
+#[derive(Debug, Deserialize)]
+pub struct ExecutionCase {
+    pub name: String,
+    /// Report fields keyed by dictionary name or tag number, MsgType is set to 8.
+    pub report: BTreeMap<String, String>,
+    /// Expected `ExecutionEvent::fields`, other event fields are not checked.
+    pub expect: BTreeMap<String, String>,
+}
+
+/// Golden test table of one plugin, loaded from YAML.
+#[derive(Debug, Deserialize)]
+pub struct ConformanceSuite {
+    /// Data dictionary used to resolve field names.
+    pub dictionary: String,
+    #[serde(default)]
+    pub orders: Vec<OrderCase>,
+    #[serde(default)]
+    pub executions: Vec<ExecutionCase>,
+}
+
+#[derive(Debug)]
+pub struct Mismatch {
+    pub case: String,
+    pub field: String,
+    pub expected: Option<String>,
+    pub actual: Option<String>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{}] {}: expected {}, got {}",
+            self.case,
+            self.field,
+            self.expected.as_deref().unwrap_or("<absent>"),
+            self.actual.as_deref().unwrap_or("<absent>")
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ConformanceReport {
+    pub cases: usize,
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl ConformanceReport {
+    pub fn passed(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+impl fmt::Display for ConformanceReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for mismatch in &self.mismatches {
+            writeln!(f, "{}", mismatch)?;
+        }
+        write!(
+            f,
+            "{} cases, {} mismatches",
+            self.cases,
+            self.mismatches.len()
+        )
+    }
+}
+
+/// Compares FIX values, numbers are compared by value so `150.5` matches `150.50`.
+fn same_value(expected: &str, actual: &str) -> bool {
+    match (expected.parse::<f64>(), actual.parse::<f64>()) {
+        (Ok(e), Ok(a)) => e == a,
+        _ => expected == actual,
+    }
+}
+
+fn get_field(msg: &Message, tag: i32) -> Option<String> {
+    msg.get_field(tag)
+        .or_else(|| msg.with_header(|h| h.get_field(tag)))
+}
+
+impl ConformanceSuite {
+    pub fn load(path: &str) -> Result<Self, QuickFixError> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| QuickFixError::invalid_argument(format!("read {path}: {e}")))?;
+        serde_yaml::from_str(&content)
+            .map_err(|e| QuickFixError::invalid_argument(format!("parse {path}: {e}")))
+    }
+
+    /// Runs every case against `plugin`, mismatches are reported by field name.
+    pub fn run(
+        &self,
+        plugin: &dyn Plugin,
+        instruments: &InstrumentStore,
+    ) -> Result<ConformanceReport, QuickFixError> {
+        let dictionary = FieldDictionary::load(&self.dictionary)?;
+        let mut report = ConformanceReport::default();
+        for case in &self.orders {
+            report.cases += 1;
+            self.check_order(case, plugin, &dictionary, &mut report)?;
+        }
+        for case in &self.executions {
+            report.cases += 1;
+            self.check_execution(case, plugin, instruments, &dictionary, &mut report)?;
+        }
+        Ok(report)
+    }
+
+    fn check_order(
+        &self,
+        case: &OrderCase,
+        plugin: &dyn Plugin,
+        dictionary: &FieldDictionary,
+        report: &mut ConformanceReport,
+    ) -> Result<(), QuickFixError> {
+        let req = case.request.to_request();
+        let converted = match case.kind {
+            MessageKind::NewOrderSingle => plugin.convert_to_new_order_single(&req),
+            MessageKind::OrderCancelRequest => plugin.convert_to_order_cancel_request(&req),
+            MessageKind::OrderCancelReplaceRequest => {
+                plugin.convert_to_order_cancel_replace_request(&req)
+            }
+        };
+        let msg = match (converted, case.expect_error) {
+            (Ok(msg), false) => msg,
+            (Err(_), true) => return Ok(()),
+            (Ok(_), true) => {
+                report.mismatches.push(Mismatch {
+                    case: case.name.clone(),
+                    field: "result".to_string(),
+                    expected: Some("error".to_string()),
+                    actual: Some("message".to_string()),
+                });
+                return Ok(());
+            }
+            (Err(e), false) => {
+                report.mismatches.push(Mismatch {
+                    case: case.name.clone(),
+                    field: "result".to_string(),
+                    expected: Some("message".to_string()),
+                    actual: Some(format!("error: {e}")),
+                });
+                return Ok(());
+            }
+        };
+
+        for (field, expected) in &case.expect {
+            let tag = resolve_tag(dictionary, field)?;
+            let actual = get_field(&msg, tag);
+            if !actual.as_deref().is_some_and(|a| same_value(expected, a)) {
+                report.mismatches.push(Mismatch {
+                    case: case.name.clone(),
+                    field: field_label(dictionary, tag),
+                    expected: Some(expected.clone()),
+                    actual,
+                });
+            }
+        }
+        for field in &case.absent {
+            let tag = resolve_tag(dictionary, field)?;
+            if let Some(actual) = get_field(&msg, tag) {
+                report.mismatches.push(Mismatch {
+                    case: case.name.clone(),
+                    field: field_label(dictionary, tag),
+                    expected: None,
+                    actual: Some(actual),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn check_execution(
+        &self,
+        case: &ExecutionCase,
+        plugin: &dyn Plugin,
+        instruments: &InstrumentStore,
+        dictionary: &FieldDictionary,
+        report: &mut ConformanceReport,
+    ) -> Result<(), QuickFixError> {
+        let mut msg = Message::new();
+        msg.with_header_mut(|h| h.set_field(MSG_TYPE, "8"))?;
+        for (field, value) in &case.report {
+            msg.set_field(resolve_tag(dictionary, field)?, value.as_str())?;
+        }
+
+        let event = match Messages::decode(msg.clone()) {
+            Ok(Messages::ExecutionReport(x)) => {
+                ExecutionEvent::translate(&x, &msg, plugin, instruments)
+            }
+            other => {
+                report.mismatches.push(Mismatch {
+                    case: case.name.clone(),
+                    field: "decode".to_string(),
+                    expected: Some("ExecutionReport".to_string()),
+                    actual: Some(format!("{other:?}")),
+                });
+                return Ok(());
+            }
+        };
+
+        let fields: HashMap<String, String> = event.fields().into_iter().collect();
+        for (field, expected) in &case.expect {
+            let actual = fields.get(field).cloned();
+            if !actual.as_deref().is_some_and(|a| same_value(expected, a)) {
+                report.mismatches.push(Mismatch {
+                    case: case.name.clone(),
+                    field: field.clone(),
+                    expected: Some(expected.clone()),
+                    actual,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+fn resolve_tag(dictionary: &FieldDictionary, field: &str) -> Result<i32, QuickFixError> {
+    field
+        .parse::<i32>()
+        .ok()
+        .or_else(|| dictionary.tag(field))
+        .ok_or_else(|| QuickFixError::invalid_argument(format!("unknown field {field}")))
+}
+
+fn field_label(dictionary: &FieldDictionary, tag: i32) -> String {
+    match dictionary.name(tag) {
+        Some(name) => format!("{name}({tag})"),
+        None => tag.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::cfg::BrokerName;
+    use crate::fix_convert::broker::Broker;
+
+    #[test]
+    fn broker1_passes_golden_cases() {
+        let instruments = Arc::new(
+            InstrumentStore::load("./config/instruments.csv", &BrokerName::Broker1).unwrap(),
+        );
+        let plugin = Broker::try_new("./config/plugin.yaml", instruments.clone()).unwrap();
+        let report = ConformanceSuite::load("./config/conformance/broker1.yaml")
+            .unwrap()
+            .run(&plugin, &instruments)
+            .unwrap();
+        assert!(report.cases > 0);
+        assert!(report.passed(), "{}", report);
+    }
+}
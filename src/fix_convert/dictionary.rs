@@ -0,0 +1,49 @@
+use std::collections::HashMap;
+
+use quickfix::QuickFixError;
+
+/// Field numbers and names of a QuickFIX data dictionary, e.g. `fantasy_FIX42.xml`.
+#[derive(Debug, Default)]
+pub struct FieldDictionary {
+    by_tag: HashMap<i32, String>,
+    by_name: HashMap<String, i32>,
+}
+
+impl FieldDictionary {
+    pub fn load(path: &str) -> Result<Self, QuickFixError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| QuickFixError::invalid_argument(format!("read {path}: {e}")))?;
+        let doc = roxmltree::Document::parse(&text)
+            .map_err(|e| QuickFixError::invalid_argument(format!("parse {path}: {e}")))?;
+
+        let mut dictionary = FieldDictionary::default();
+        for node in doc.descendants().filter(|n| n.has_tag_name("field")) {
+            let (Some(number), Some(name)) = (node.attribute("number"), node.attribute("name"))
+            else {
+                continue;
+            };
+            let tag = number.parse::<i32>().map_err(|e| {
+                QuickFixError::invalid_argument(format!("field {name} number {number}: {e}"))
+            })?;
+            dictionary.by_tag.insert(tag, name.to_string());
+            dictionary.by_name.insert(name.to_string(), tag);
+        }
+        Ok(dictionary)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_tag.is_empty()
+    }
+
+    pub fn contains(&self, tag: i32) -> bool {
+        self.by_tag.contains_key(&tag)
+    }
+
+    pub fn name(&self, tag: i32) -> Option<&str> {
+        self.by_tag.get(&tag).map(String::as_str)
+    }
+
+    pub fn tag(&self, name: &str) -> Option<i32> {
+        self.by_name.get(name).copied()
+    }
+}
@@ -1,19 +1,36 @@
-use crate::server::fantasy::RequestMessage;
-use fantasy_fix42::NewOrderSingle;
+use crate::server::fantasy::{RequestMessage, TimeInForce};
+use quickfix::Message;
 
-pub trait Plugin {
+pub trait Plugin: Send + Sync {
     fn convert_to_new_order_single(
         &self,
         order: &RequestMessage,
-    ) -> Result<NewOrderSingle, quickfix::QuickFixError>;
+    ) -> Result<Message, quickfix::QuickFixError>;
 
     fn convert_to_order_cancel_replace_request(
         &self,
         order: &RequestMessage,
-    ) -> Result<NewOrderSingle, quickfix::QuickFixError>;
+    ) -> Result<Message, quickfix::QuickFixError>;
 
     fn convert_to_order_cancel_request(
         &self,
         order: &RequestMessage,
-    ) -> Result<NewOrderSingle, quickfix::QuickFixError>;
+    ) -> Result<Message, quickfix::QuickFixError>;
+
+    /// Checks the client supplied custom tags of an order, so a request with
+    /// tags the broker would not get is refused before it is booked.
+    fn check_extra_tags(&self, _order: &RequestMessage) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Inbound custom tags copied from execution reports onto execution events.
+    fn echo_tags(&self) -> Vec<i32> {
+        Vec::new()
+    }
+
+    /// Whether the broker expires orders of this TimeInForce itself, the
+    /// gateway cancels the others at expiry.
+    fn expires_natively(&self, _time_in_force: TimeInForce) -> bool {
+        true
+    }
 }
@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+
+use log::debug;
+use quickfix::{FieldMap, Message, QuickFixError};
+use serde::Deserialize;
+
+use crate::fix_convert::dictionary::FieldDictionary;
+use crate::server::fantasy::{RequestMessage, Side};
+
+/// Outgoing message types a mapping rule can be restricted to.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    NewOrderSingle,
+    OrderCancelRequest,
+    OrderCancelReplaceRequest,
+}
+
+/// Side of a rule condition, `Buy` or `Sell` in any case.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub enum RuleSide {
+    Buy,
+    Sell,
+}
+
+impl TryFrom<String> for RuleSide {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        if value.eq_ignore_ascii_case("buy") {
+            Ok(RuleSide::Buy)
+        } else if value.eq_ignore_ascii_case("sell") {
+            Ok(RuleSide::Sell)
+        } else {
+            Err(format!("unknown side {value:?}, expected Buy or Sell"))
+        }
+    }
+}
+
+/// Request fields a rule is matched against, unset fields match anything.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct RuleCondition {
+    pub account: Option<String>,
+    pub symbol: Option<String>,
+    pub side: Option<RuleSide>,
+}
+
+impl RuleCondition {
+    fn matches(&self, req: &RequestMessage) -> bool {
+        let side = match req.side() {
+            Side::Buy => Some(RuleSide::Buy),
+            Side::Sell => Some(RuleSide::Sell),
+            Side::Unspecified => None,
+        };
+        self.account.as_ref().is_none_or(|v| *v == req.account)
+            && self.symbol.as_ref().is_none_or(|v| *v == req.symbol)
+            && self.side.is_none_or(|v| Some(v) == side)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Transform {
+    Uppercase,
+    Lowercase,
+    Prefix {
+        value: String,
+    },
+    Suffix {
+        value: String,
+    },
+    Replace {
+        from: String,
+        to: String,
+    },
+    /// Value lookup table, values missing from the table are left untouched.
+    Map {
+        table: HashMap<String, String>,
+    },
+}
+
+impl Transform {
+    fn apply(&self, value: &str) -> String {
+        match self {
+            Transform::Uppercase => value.to_uppercase(),
+            Transform::Lowercase => value.to_lowercase(),
+            Transform::Prefix { value: prefix } => format!("{prefix}{value}"),
+            Transform::Suffix { value: suffix } => format!("{value}{suffix}"),
+            Transform::Replace { from, to } => value.replace(from.as_str(), to),
+            Transform::Map { table } => table.get(value).cloned().unwrap_or(value.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum FieldAction {
+    Set { tag: i32, value: String },
+    Rename { from: i32, to: i32 },
+    Remove { tag: i32 },
+    Transform { tag: i32, transform: Transform },
+}
+
+impl FieldAction {
+    fn tags(&self) -> Vec<i32> {
+        match self {
+            FieldAction::Set { tag, .. }
+            | FieldAction::Remove { tag }
+            | FieldAction::Transform { tag, .. } => vec![*tag],
+            FieldAction::Rename { from, to } => vec![*from, *to],
+        }
+    }
+
+    fn apply(&self, msg: &mut Message) -> Result<(), QuickFixError> {
+        match self {
+            FieldAction::Set { tag, value } => msg.set_field(*tag, value.as_str()),
+            FieldAction::Rename { from, to } => {
+                if let Some(value) = msg.get_field(*from) {
+                    msg.remove_field(*from)?;
+                    msg.set_field(*to, value.as_str())?;
+                }
+                Ok(())
+            }
+            FieldAction::Remove { tag } => msg.remove_field(*tag),
+            FieldAction::Transform { tag, transform } => {
+                if let Some(value) = msg.get_field(*tag) {
+                    msg.set_field(*tag, transform.apply(&value).as_str())?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MappingRule {
+    #[serde(default)]
+    pub name: String,
+    /// Message types the rule applies to, empty means all of them.
+    #[serde(default)]
+    pub messages: Vec<MessageKind>,
+    #[serde(default)]
+    pub when: RuleCondition,
+    pub actions: Vec<FieldAction>,
+}
+
+impl MappingRule {
+    fn matches(&self, kind: MessageKind, req: &RequestMessage) -> bool {
+        (self.messages.is_empty() || self.messages.contains(&kind)) && self.when.matches(req)
+    }
+}
+
+/// Ordered list of mapping rules loaded from the plugin configuration file.
+///
+/// Every matching rule is applied in file order, so a later rule sees the
+/// fields written by an earlier one.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(transparent)]
+pub struct MappingRules {
+    rules: Vec<MappingRule>,
+}
+
+impl MappingRules {
+    /// Checks the tags of every action: positive, and known to `dictionary`
+    /// or listed in `allowed` when a dictionary is loaded.
+    pub fn validate(&self, dictionary: &FieldDictionary, allowed: &[i32]) -> Result<(), String> {
+        for rule in &self.rules {
+            for tag in rule.actions.iter().flat_map(FieldAction::tags) {
+                if tag <= 0 {
+                    return Err(format!("mapping rule [{}]: invalid tag {tag}", rule.name));
+                }
+                if !dictionary.is_empty() && !dictionary.contains(tag) && !allowed.contains(&tag) {
+                    return Err(format!(
+                        "mapping rule [{}]: tag {tag} is not in the dictionary or allow-list",
+                        rule.name
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn apply(
+        &self,
+        kind: MessageKind,
+        req: &RequestMessage,
+        msg: &mut Message,
+    ) -> Result<(), QuickFixError> {
+        for rule in self.rules.iter().filter(|r| r.matches(kind, req)) {
+            debug!("apply mapping rule [{}] to {:?}", rule.name, kind);
+            for action in &rule.actions {
+                action.apply(msg)?;
+            }
+        }
+        Ok(())
+    }
+}
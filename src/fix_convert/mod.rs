@@ -1,2 +1,6 @@
 pub mod broker;
+pub mod conformance;
+pub mod dictionary;
 pub mod gw_plugin;
+pub mod mapping;
+pub mod reload;
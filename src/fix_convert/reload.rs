@@ -0,0 +1,115 @@
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use log::{error, info};
+use quickfix::{Message, QuickFixError};
+use tokio::time::sleep;
+
+use crate::fix_convert::gw_plugin::Plugin;
+use crate::server::fantasy::{RequestMessage, TimeInForce};
+
+/// Builds a plugin from the current content of its configuration file.
+pub type PluginLoader = Box<dyn Fn() -> Result<Arc<dyn Plugin>, QuickFixError> + Send + Sync>;
+
+/// Plugin whose configuration can be swapped while the FIX session stays up.
+///
+/// Every conversion runs against the plugin that was current when it started,
+/// so a reload never changes an order half way through.
+pub struct ReloadablePlugin {
+    path: String,
+    loader: PluginLoader,
+    current: RwLock<Arc<dyn Plugin>>,
+}
+
+impl ReloadablePlugin {
+    pub fn try_new(path: &str, loader: PluginLoader) -> Result<Self, QuickFixError> {
+        let current = loader()?;
+        Ok(ReloadablePlugin {
+            path: path.to_string(),
+            loader,
+            current: RwLock::new(current),
+        })
+    }
+
+    pub fn current(&self) -> Arc<dyn Plugin> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Loads and validates the configuration again, the running plugin is
+    /// only replaced once the new one has been built successfully.
+    pub fn reload(&self) -> Result<(), QuickFixError> {
+        let plugin = (self.loader)()?;
+        *self.current.write().unwrap() = plugin;
+        info!("plugin config {} reloaded", self.path);
+        Ok(())
+    }
+
+    /// Polls the modification time of the configuration file and reloads on
+    /// change. A failed reload is tried again on every poll until it succeeds
+    /// (a file the dictionary it refers to was missing for, or read half
+    /// written), its error is logged once per change of the file.
+    pub async fn watch(self: Arc<Self>, interval: Duration) {
+        let mut last_modified = modified(&self.path);
+        let mut failed: Option<Option<SystemTime>> = None;
+        loop {
+            sleep(interval).await;
+            let modified = modified(&self.path);
+            if modified == last_modified && failed.is_none() {
+                continue;
+            }
+            last_modified = modified;
+            match self.reload() {
+                Ok(()) => failed = None,
+                Err(e) => {
+                    if failed != Some(modified) {
+                        error!(
+                            "reload plugin config {} failed, keeping previous config: {}",
+                            self.path, e
+                        );
+                    }
+                    failed = Some(modified);
+                }
+            }
+        }
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+impl Plugin for ReloadablePlugin {
+    fn convert_to_new_order_single(
+        &self,
+        order: &RequestMessage,
+    ) -> Result<Message, QuickFixError> {
+        self.current().convert_to_new_order_single(order)
+    }
+
+    fn convert_to_order_cancel_replace_request(
+        &self,
+        order: &RequestMessage,
+    ) -> Result<Message, QuickFixError> {
+        self.current()
+            .convert_to_order_cancel_replace_request(order)
+    }
+
+    fn convert_to_order_cancel_request(
+        &self,
+        order: &RequestMessage,
+    ) -> Result<Message, QuickFixError> {
+        self.current().convert_to_order_cancel_request(order)
+    }
+
+    fn check_extra_tags(&self, order: &RequestMessage) -> Result<(), String> {
+        self.current().check_extra_tags(order)
+    }
+
+    fn echo_tags(&self) -> Vec<i32> {
+        self.current().echo_tags()
+    }
+
+    fn expires_natively(&self, time_in_force: TimeInForce) -> bool {
+        self.current().expires_natively(time_in_force)
+    }
+}
@@ -0,0 +1,241 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::error;
+use serde::{Deserialize, Serialize};
+use tonic::{Code, Status};
+
+use crate::store::{Journal, Record};
+
+/// Outcome of an order entry RPC: the ClOrdID sent, or the error returned.
+pub type Outcome = Result<String, Status>;
+
+/// Outcome of a request with an idempotency key, as journaled.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyOutcome {
+    pub client_id: String,
+    pub key: String,
+    pub method: String,
+    pub at_ms: i64,
+    /// ClOrdID sent, or the gRPC code and message of the error returned.
+    pub outcome: Result<String, (i32, String)>,
+}
+
+struct Entry {
+    method: String,
+    /// None while the first request is in flight.
+    outcome: Option<Outcome>,
+}
+
+/// Outcomes of order entry RPCs by (client id, idempotency key), kept for the
+/// retention window so a retried request gets the first answer back. They are
+/// journaled, so a retry after a restart is answered too.
+pub struct IdempotencyCache {
+    retention_ms: i64,
+    entries: HashMap<(String, String), Entry>,
+    /// Keys by completion time in ms, oldest first.
+    expiry: VecDeque<(i64, (String, String))>,
+    journal: Arc<Journal>,
+}
+
+/// What to do with a request carrying an idempotency key.
+pub enum Begin<'a> {
+    /// Work the request and complete the key with its outcome.
+    Start(InFlight<'a>),
+    /// Answer with this outcome and do nothing else.
+    Answer(Outcome),
+}
+
+/// A key marked in flight while its request is worked. Dropped before
+/// `complete`, the request failed before anything was booked or was
+/// abandoned, and the key is forgotten so a retry goes through.
+pub struct InFlight<'a> {
+    cache: &'a Mutex<IdempotencyCache>,
+    key: Option<(String, String, &'static str)>,
+}
+
+impl InFlight<'_> {
+    pub fn complete(mut self, outcome: &Outcome) {
+        if let Some((client_id, key, method)) = self.key.take() {
+            let mut cache = self.cache.lock().unwrap();
+            cache.insert(&client_id, &key, method, outcome.clone());
+        }
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if let Some((client_id, key, _)) = self.key.take() {
+            self.cache.lock().unwrap().entries.remove(&(client_id, key));
+        }
+    }
+}
+
+impl IdempotencyCache {
+    /// A zero retention disables the cache.
+    pub fn new(retention: Duration, journal: Arc<Journal>) -> Self {
+        IdempotencyCache {
+            retention_ms: retention.as_millis() as i64,
+            entries: HashMap::new(),
+            expiry: VecDeque::new(),
+            journal,
+        }
+    }
+
+    /// Outcomes journaled before a restart, oldest first.
+    pub fn restore(&mut self, outcomes: Vec<KeyOutcome>) {
+        for outcome in outcomes {
+            let key = (outcome.client_id.clone(), outcome.key.clone());
+            self.expiry.push_back((outcome.at_ms, key.clone()));
+            let entry = Entry {
+                method: outcome.method,
+                outcome: Some(
+                    outcome
+                        .outcome
+                        .map_err(|(code, message)| Status::new(Code::from_i32(code), message)),
+                ),
+            };
+            self.entries.insert(key, entry);
+        }
+        self.purge(chrono::Utc::now().timestamp_millis());
+    }
+
+    fn enabled(&self, key: &str) -> bool {
+        self.retention_ms > 0 && !key.is_empty()
+    }
+
+    /// Starts a request: the outcome of an earlier request with the same key
+    /// is returned instead, a key in flight or used before by another RPC is
+    /// refused. The cache is only locked for the lookup.
+    pub fn begin<'a>(
+        cache: &'a Mutex<Self>,
+        client_id: &str,
+        key: &str,
+        method: &'static str,
+    ) -> Begin<'a> {
+        let mut this = cache.lock().unwrap();
+        if !this.enabled(key) {
+            return Begin::Start(InFlight { cache, key: None });
+        }
+        this.purge(chrono::Utc::now().timestamp_millis());
+        let id = (client_id.to_string(), key.to_string());
+        match this.entries.get(&id) {
+            Some(entry) if entry.method != method => Begin::Answer(Err(Status::invalid_argument(
+                format!("idempotency key {} already used by {}", key, entry.method),
+            ))),
+            Some(Entry {
+                outcome: Some(outcome),
+                ..
+            }) => Begin::Answer(outcome.clone()),
+            Some(_) => Begin::Answer(Err(Status::aborted(format!(
+                "request with idempotency key {} in progress",
+                key
+            )))),
+            None => {
+                this.entries.insert(
+                    id,
+                    Entry {
+                        method: method.to_string(),
+                        outcome: None,
+                    },
+                );
+                Begin::Start(InFlight {
+                    cache,
+                    key: Some((client_id.to_string(), key.to_string(), method)),
+                })
+            }
+        }
+    }
+
+    fn insert(&mut self, client_id: &str, key: &str, method: &'static str, outcome: Outcome) {
+        let at_ms = chrono::Utc::now().timestamp_millis();
+        let journaled = KeyOutcome {
+            client_id: client_id.to_string(),
+            key: key.to_string(),
+            method: method.to_string(),
+            at_ms,
+            outcome: outcome
+                .clone()
+                .map_err(|status| (status.code() as i32, status.message().to_string())),
+        };
+        if let Err(e) = self.journal.append(&Record::Idempotency(journaled)) {
+            error!("journal idempotency key {} failed: {}", key, e);
+        }
+        let id = (client_id.to_string(), key.to_string());
+        self.expiry.push_back((at_ms, id.clone()));
+        self.entries.insert(
+            id,
+            Entry {
+                method: method.to_string(),
+                outcome: Some(outcome),
+            },
+        );
+    }
+
+    fn purge(&mut self, now_ms: i64) {
+        while let Some((at_ms, _)) = self.expiry.front() {
+            if now_ms - at_ms < self.retention_ms {
+                break;
+            }
+            let (_, key) = self.expiry.pop_front().unwrap();
+            // an entry in flight again after its outcome expired is kept
+            if self.entries.get(&key).is_some_and(|e| e.outcome.is_some()) {
+                self.entries.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_log::EventLog;
+
+    fn cache() -> Mutex<IdempotencyCache> {
+        let journal = Journal::open(
+            "",
+            &Default::default(),
+            &mut EventLog::new(&Default::default()).unwrap(),
+        )
+        .unwrap()
+        .0;
+        Mutex::new(IdempotencyCache::new(
+            Duration::from_secs(60),
+            Arc::new(journal),
+        ))
+    }
+
+    fn answer(begin: Begin) -> Result<String, Code> {
+        match begin {
+            Begin::Start(_) => panic!("request started"),
+            Begin::Answer(outcome) => outcome.map_err(|status| status.code()),
+        }
+    }
+
+    #[test]
+    fn retry_in_flight_refused_then_answered() {
+        let cache = cache();
+        let Begin::Start(first) = IdempotencyCache::begin(&cache, "c1", "k1", "submit") else {
+            panic!("first request answered");
+        };
+        let retry = answer(IdempotencyCache::begin(&cache, "c1", "k1", "submit"));
+        assert_eq!(retry, Err(Code::Aborted));
+        first.complete(&Ok("ID1".to_string()));
+        let retry = answer(IdempotencyCache::begin(&cache, "c1", "k1", "submit"));
+        assert_eq!(retry, Ok("ID1".to_string()));
+        let other = answer(IdempotencyCache::begin(&cache, "c1", "k1", "cancel"));
+        assert_eq!(other, Err(Code::InvalidArgument));
+    }
+
+    #[test]
+    fn abandoned_key_forgotten() {
+        let cache = cache();
+        let first = IdempotencyCache::begin(&cache, "c1", "k1", "submit");
+        drop(first);
+        assert!(matches!(
+            IdempotencyCache::begin(&cache, "c1", "k1", "submit"),
+            Begin::Start(_)
+        ));
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+
+use log::info;
+use serde::Deserialize;
+
+use crate::cfg::BrokerName;
+
+/// One row of the instrument master, mapping an internal symbol to the
+/// symbology a given broker expects.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Instrument {
+    pub symbol: String,
+    pub broker: BrokerName,
+    pub broker_symbol: String,
+    #[serde(default)]
+    pub security_id: Option<String>,
+    #[serde(default)]
+    pub id_source: Option<String>,
+    #[serde(default)]
+    pub security_exchange: Option<String>,
+    #[serde(default)]
+    pub currency: Option<String>,
+    #[serde(default)]
+    pub tick_size: Option<f64>,
+    #[serde(default)]
+    pub lot_size: Option<f64>,
+}
+
+impl Instrument {
+    /// Checks price and quantity against the tick and lot size.
+    pub fn validate(&self, price: Option<f64>, quantity: f64) -> Result<(), String> {
+        match (self.tick_size, price) {
+            (Some(tick), Some(price)) if !is_multiple_of(price, tick) => {
+                return Err(format!(
+                    "price {} of {} is not a multiple of tick size {}",
+                    price, self.symbol, tick
+                ));
+            }
+            _ => {}
+        }
+        match self.lot_size {
+            Some(lot) if !is_multiple_of(quantity, lot) => Err(format!(
+                "quantity {} of {} is not a multiple of lot size {}",
+                quantity, self.symbol, lot
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn is_multiple_of(value: f64, step: f64) -> bool {
+    if step <= 0.0 {
+        return true;
+    }
+    let ratio = value / step;
+    (ratio - ratio.round()).abs() < 1e-9
+}
+
+/// Instrument reference data for the broker this gateway is connected to.
+#[derive(Debug, Default)]
+pub struct InstrumentStore {
+    by_symbol: HashMap<String, Instrument>,
+    by_broker_symbol: HashMap<String, String>,
+}
+
+impl InstrumentStore {
+    /// Loads the instrument master from a `.csv` or `.yaml` file, keeping only
+    /// the rows of `broker`. An empty path gives an empty store.
+    pub fn load(path: &str, broker: &BrokerName) -> Result<Self, Box<dyn Error>> {
+        if path.is_empty() {
+            return Ok(InstrumentStore::default());
+        }
+        let instruments: Vec<Instrument> = match Path::new(path).extension() {
+            Some(ext) if ext == "csv" => csv::Reader::from_path(path)?
+                .deserialize()
+                .collect::<Result<_, _>>()?,
+            _ => serde_yaml::from_str(&std::fs::read_to_string(path)?)?,
+        };
+
+        let mut store = InstrumentStore::default();
+        for instrument in instruments.into_iter().filter(|i| i.broker == *broker) {
+            store
+                .by_broker_symbol
+                .insert(instrument.broker_symbol.clone(), instrument.symbol.clone());
+            store
+                .by_symbol
+                .insert(instrument.symbol.clone(), instrument);
+        }
+        info!("loaded {} instruments from {}", store.by_symbol.len(), path);
+        Ok(store)
+    }
+
+    pub fn get(&self, symbol: &str) -> Option<&Instrument> {
+        self.by_symbol.get(symbol)
+    }
+
+    /// Maps a broker symbol back to ours, unknown symbols are returned as is.
+    pub fn internal_symbol<'a>(&'a self, broker_symbol: &'a str) -> &'a str {
+        self.by_broker_symbol
+            .get(broker_symbol)
+            .map(String::as_str)
+            .unwrap_or(broker_symbol)
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::server::fantasy::Halt as HaltReport;
+use crate::store::{Journal, Record};
+
+/// Who halted trading, when and why.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Halt {
+    pub operator: String,
+    pub reason: String,
+    pub since_ms: i64,
+}
+
+/// Trading halts, gateway-wide or per account. A halt blocks new orders and
+/// replaces until it is explicitly resumed, and is journaled so it survives a
+/// restart.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Halts {
+    pub gateway: Option<Halt>,
+    pub accounts: BTreeMap<String, Halt>,
+}
+
+pub struct KillSwitch {
+    halts: Halts,
+    journal: Arc<Journal>,
+}
+
+impl KillSwitch {
+    pub fn new(journal: Arc<Journal>, halts: Halts) -> Self {
+        KillSwitch { halts, journal }
+    }
+
+    /// The halt blocking orders of `account`, the gateway-wide one first.
+    pub fn halted(&self, account: &str) -> Option<&Halt> {
+        self.halts
+            .gateway
+            .as_ref()
+            .or_else(|| self.halts.accounts.get(account))
+    }
+
+    /// Halts `account`, or the whole gateway when it is empty.
+    pub fn halt(&mut self, account: &str, halt: Halt) {
+        warn!(
+            "kill switch {} by {}: {}",
+            scope(account),
+            halt.operator,
+            halt.reason
+        );
+        if account.is_empty() {
+            self.halts.gateway = Some(halt);
+        } else {
+            self.halts.accounts.insert(account.to_string(), halt);
+        }
+        self.save();
+    }
+
+    /// Lifts the halt of `account`, or the gateway-wide one when it is empty.
+    /// False when there was none.
+    pub fn resume(&mut self, account: &str, operator: &str) -> bool {
+        let lifted = if account.is_empty() {
+            self.halts.gateway.take().is_some()
+        } else {
+            self.halts.accounts.remove(account).is_some()
+        };
+        if lifted {
+            warn!("resume {} by {}", scope(account), operator);
+            self.save();
+        }
+        lifted
+    }
+
+    pub fn reports(&self) -> Vec<HaltReport> {
+        self.halts
+            .gateway
+            .iter()
+            .map(|halt| ("", halt))
+            .chain(
+                self.halts
+                    .accounts
+                    .iter()
+                    .map(|(account, halt)| (account.as_str(), halt)),
+            )
+            .map(|(account, halt)| HaltReport {
+                account: account.to_string(),
+                operator: halt.operator.clone(),
+                reason: halt.reason.clone(),
+                since_ms: halt.since_ms,
+            })
+            .collect()
+    }
+
+    fn save(&self) {
+        if let Err(e) = self.journal.append(&Record::KillSwitch(self.halts.clone())) {
+            error!("journal kill switch failed: {}", e);
+        }
+    }
+}
+
+pub fn scope(account: &str) -> String {
+    if account.is_empty() {
+        "gateway".to_string()
+    } else {
+        format!("account {}", account)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_log::EventLog;
+
+    fn kill_switch() -> KillSwitch {
+        let journal = Journal::open(
+            "",
+            &Default::default(),
+            &mut EventLog::new(&Default::default()).unwrap(),
+        )
+        .unwrap()
+        .0;
+        KillSwitch::new(Arc::new(journal), Halts::default())
+    }
+
+    fn halt(reason: &str) -> Halt {
+        Halt {
+            operator: "ops".to_string(),
+            reason: reason.to_string(),
+            since_ms: 0,
+        }
+    }
+
+    fn reason(kill_switch: &KillSwitch, account: &str) -> Option<String> {
+        kill_switch.halted(account).map(|h| h.reason.clone())
+    }
+
+    #[test]
+    fn gateway_halt_comes_before_account_halts() {
+        let mut ks = kill_switch();
+        ks.halt("acc", halt("account"));
+        assert_eq!(reason(&ks, "acc").as_deref(), Some("account"));
+        assert_eq!(reason(&ks, "other"), None);
+
+        ks.halt("", halt("gateway"));
+        assert_eq!(reason(&ks, "acc").as_deref(), Some("gateway"));
+        assert_eq!(reason(&ks, "other").as_deref(), Some("gateway"));
+
+        // lifting one halt leaves the other in force
+        assert!(ks.resume("", "ops"));
+        assert_eq!(reason(&ks, "acc").as_deref(), Some("account"));
+        assert!(!ks.resume("", "ops"));
+        assert!(ks.resume("acc", "ops"));
+        assert_eq!(reason(&ks, "acc"), None);
+    }
+}
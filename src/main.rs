@@ -1,4 +1,5 @@
 use config::{Config, Environment, File};
+use fix_convert::conformance::ConformanceSuite;
 // grpc
 use fantasy::{RequestMessage, ResponseMessage};
 use server::fantasy::example_service_server::ExampleServiceServer;
@@ -10,12 +11,29 @@ use std::{env, io::Write, net::SocketAddr, sync::Arc, thread};
 use tokio::runtime::Handle;
 use tokio::sync::{Mutex, mpsc};
 
+pub mod algo;
 pub mod cfg;
+pub mod cl_ord_id;
+pub mod disconnect;
+pub mod duplicate;
+pub mod event_log;
+pub mod execution;
+pub mod expiry;
+pub mod exposure;
 pub mod fix_client;
 pub mod fix_convert;
+pub mod idempotency;
+pub mod instrument;
+pub mod kill_switch;
 pub mod order_manager;
+pub mod position;
+pub mod risk;
+pub mod self_trade;
 pub mod server;
 pub mod shared_data;
+pub mod store;
+pub mod symbol_lists;
+pub mod throttle;
 
 pub use cfg::GwConfig;
 pub use fix_client::*;
@@ -31,7 +49,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let args: Vec<_> = env::args().collect();
     let Some(cfg) = args.get(1) else {
-        error!("Bad program usage: {} <config_file>", args[0]);
+        error!(
+            "Bad program usage: {} <config_file> [--conformance <suite_file>]",
+            args[0]
+        );
         return Err("Bad program usage: <config_file> argument missing".into());
     };
 
@@ -47,25 +68,92 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // recv order from grpc forward to quickfix
     let (order_sender, mut order_receiver) = mpsc::unbounded_channel::<ForwardRequest>();
 
-    let config_file = gw_config.fix_cfg.clone();
-    let shared_data = Arc::new(Mutex::new(shared_data::SharedData::new()));
-    let data_clone = shared_data.clone();
+    let instruments = Arc::new(instrument::InstrumentStore::load(
+        &gw_config.instrument_file,
+        &gw_config.broker_name,
+    )?);
+
+    // check the plugin against a golden test table and exit
+    if let (Some("--conformance"), Some(suite)) = (args.get(2).map(String::as_str), args.get(3)) {
+        let plugin = create_plugin(&gw_config, instruments.clone())?;
+        let report = ConformanceSuite::load(suite)?.run(plugin.as_ref(), &instruments)?;
+        info!("{}", report);
+        return if report.passed() {
+            Ok(())
+        } else {
+            Err("plugin conformance failed".into())
+        };
+    }
+
+    let cl_ord_ids =
+        cl_ord_id::ClOrdIdGenerator::load(&gw_config.cl_ord_id_prefix, &gw_config.cl_ord_id_file)?;
+    let duplicates = duplicate::DuplicateFilter::load(&gw_config.duplicate_check)?;
+
+    // rebuild the books from the journal of the previous run
+    let mut events = event_log::EventLog::new(&gw_config.event_log)?;
+    let (max_events, event_age_ms) = events.retention();
+    let retention = store::Retention {
+        idempotency_ms: gw_config.idempotency_retention_secs as i64 * 1000,
+        max_events,
+        event_age_ms,
+    };
+    let (journal, recovered) =
+        store::Journal::open(&gw_config.store_file, &retention, &mut events)?;
+    let journal = Arc::new(journal);
+    let mut order_manager =
+        order_manager::OrderManager::new(cl_ord_ids, duplicates, journal.clone());
+    order_manager.restore(recovered.orders, recovered.cancel_requests);
+    let mut positions = position::PositionBook::new();
+    for fill in &recovered.fills {
+        positions.apply_fill(fill);
+    }
+    let mut parents = algo::ParentBook::new(journal.clone(), instruments.clone());
+    parents.restore(recovered.parents);
+    let kill_switch = kill_switch::KillSwitch::new(journal.clone(), recovered.halts);
+    let symbol_lists = symbol_lists::SymbolLists::load(&gw_config.symbol_lists_file)?;
+    events.restore(recovered.events);
+
+    let events = Arc::new(Mutex::new(events));
+    let events_clone = events.clone();
+    let books = shared_data::Books {
+        orders: Arc::new(Mutex::new(order_manager)),
+        positions: Arc::new(Mutex::new(positions)),
+        parents: Arc::new(Mutex::new(parents)),
+        journal,
+        throttle: Arc::new(Mutex::new(throttle::Throttle::new(&gw_config.throttle))),
+        kill_switch: Arc::new(Mutex::new(kill_switch)),
+        symbol_lists: Arc::new(Mutex::new(symbol_lists)),
+    };
+    let books_clone = books.clone();
     let gw_config_clone = gw_config.clone();
+    let plugin = create_plugin(&gw_config, instruments.clone())?;
+    let plugin_clone = plugin.clone();
     let handle = Handle::current();
     thread::spawn(move || {
         if let Err(e) = start_quickfix_server(
-            config_file,
             &mut order_receiver,
-            data_clone,
+            events_clone,
             handle,
             gw_config_clone,
+            plugin_clone,
+            instruments,
+            books_clone,
         ) {
             error!("start_quickfix_server error: {}", e);
         }
     });
 
     let addr: SocketAddr = gw_config.address.parse()?;
-    let example_service = MyExampleService::new(order_sender, shared_data, gw_config);
+    let example_service = Arc::new(MyExampleService::new(
+        order_sender,
+        events,
+        gw_config,
+        books,
+        plugin,
+    ));
+    example_service.restore_idempotency(recovered.idempotency);
+    example_service.watch_disconnects();
+    example_service.work_parent_orders();
 
     // https://medium.com/@drewjaja/how-to-add-grpc-reflection-with-rust-tonic-reflection-1f4e14e6750e
     let reflection_service = tonic_reflection::server::Builder::configure()
@@ -74,7 +162,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     info!("Server listening on {}", addr);
 
     Server::builder()
-        .add_service(ExampleServiceServer::new(example_service))
+        .add_service(ExampleServiceServer::from_arc(example_service))
         .add_service(reflection_service)
         .serve(addr)
         .await?;
@@ -1,22 +1,1114 @@
-use std::collections::HashSet;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
 
+use chrono::{DateTime, Utc};
+use fantasy_fix42::field_types::{ExecTransType, ExecType, OrdStatus};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::cl_ord_id::ClOrdIdGenerator;
+use crate::duplicate::DuplicateFilter;
+use crate::execution::ExecutionEvent;
+use crate::server::fantasy::{MassCancelRequest, OrderReport, RequestMessage, Side};
+use crate::store::{CancelRequest, Journal, Record};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OrderState {
+    PendingNew,
+    New,
+    PartiallyFilled,
+    Filled,
+    PendingCancel,
+    PendingReplace,
+    Canceled,
+    Replaced,
+    Rejected,
+    Expired,
+}
+
+impl OrderState {
+    fn from_ord_status(status: OrdStatus) -> Option<Self> {
+        match status {
+            OrdStatus::PendingNew => Some(OrderState::PendingNew),
+            OrdStatus::New => Some(OrderState::New),
+            OrdStatus::PartiallyFilled => Some(OrderState::PartiallyFilled),
+            OrdStatus::Filled => Some(OrderState::Filled),
+            OrdStatus::PendingCancel => Some(OrderState::PendingCancel),
+            OrdStatus::PendingReplace => Some(OrderState::PendingReplace),
+            OrdStatus::Canceled => Some(OrderState::Canceled),
+            OrdStatus::Replaced => Some(OrderState::Replaced),
+            OrdStatus::Rejected => Some(OrderState::Rejected),
+            OrdStatus::Expired => Some(OrderState::Expired),
+            _ => None,
+        }
+    }
+
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            OrderState::Filled
+                | OrderState::Canceled
+                | OrderState::Replaced
+                | OrderState::Rejected
+                | OrderState::Expired
+        )
+    }
+
+    fn can_move_to(&self, to: OrderState) -> bool {
+        use OrderState::*;
+        if *self == to {
+            return !self.is_terminal();
+        }
+        match self {
+            PendingNew => matches!(
+                to,
+                New | PartiallyFilled | Filled | PendingCancel | Canceled | Rejected | Expired
+            ),
+            New => matches!(
+                to,
+                PartiallyFilled
+                    | Filled
+                    | PendingCancel
+                    | PendingReplace
+                    | Canceled
+                    | Replaced
+                    | Expired
+            ),
+            PartiallyFilled => matches!(
+                to,
+                Filled | PendingCancel | PendingReplace | Canceled | Replaced | Expired
+            ),
+            // fills can still arrive, and a rejected cancel or replace goes back to the previous state
+            PendingCancel => matches!(
+                to,
+                PendingNew
+                    | New
+                    | PartiallyFilled
+                    | Filled
+                    | PendingReplace
+                    | Canceled
+                    | Replaced
+                    | Expired
+            ),
+            PendingReplace => matches!(
+                to,
+                New | PartiallyFilled | Filled | PendingCancel | Canceled | Replaced | Expired
+            ),
+            Filled | Canceled | Replaced | Rejected | Expired => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Order {
+    pub cl_ord_id: String,
+    /// ClOrdID of the first order of the replace chain, the order's own if it replaces none.
+    #[serde(default)]
+    pub chain_id: String,
+    pub client_id: String,
+    /// Reference the client gave for the order, if any.
+    pub client_ref: Option<String>,
+    pub orig_cl_ord_id: Option<String>,
+    pub order_id: Option<String>,
+    pub account: String,
+    pub symbol: String,
+    #[serde(with = "side_number")]
+    pub side: Side,
+    pub price: f64,
+    pub quantity: f64,
+    pub state: OrderState,
+    pub cum_qty: f64,
+    pub leaves_qty: f64,
+    pub avg_px: f64,
+    /// State to go back to when a cancel or replace request is rejected.
+    state_before_cancel: Option<OrderState>,
+    /// ExecIDs of the execution reports applied to the order.
+    #[serde(default)]
+    exec_ids: Vec<String>,
+    /// When the gateway cancels the order itself (ms since epoch), for a
+    /// TimeInForce the broker does not handle.
+    #[serde(default)]
+    expire_at: Option<i64>,
+}
+
+impl Order {
+    pub fn to_report(&self) -> OrderReport {
+        OrderReport {
+            cl_ord_id: self.cl_ord_id.clone(),
+            chain_id: self.chain_id.clone(),
+            client_ref: self.client_ref.clone().unwrap_or_default(),
+            orig_cl_ord_id: self.orig_cl_ord_id.clone().unwrap_or_default(),
+            order_id: self.order_id.clone().unwrap_or_default(),
+            account: self.account.clone(),
+            symbol: self.symbol.clone(),
+            side: self.side as i32,
+            price: self.price,
+            quantity: self.quantity,
+            state: format!("{:?}", self.state),
+            cum_qty: self.cum_qty,
+            leaves_qty: self.leaves_qty,
+            avg_px: self.avg_px,
+        }
+    }
+
+    /// Cancel request of the order, without client reference.
+    pub fn cancel_request(&self) -> RequestMessage {
+        RequestMessage {
+            orig_cl_ord_id: self.cl_ord_id.clone(),
+            client_id: self.client_id.clone(),
+            account: self.account.clone(),
+            symbol: self.symbol.clone(),
+            side: self.side as i32,
+            quantity: self.quantity,
+            ..Default::default()
+        }
+    }
+
+    fn from_request(req: &RequestMessage, client_ref: Option<String>) -> Self {
+        Order {
+            cl_ord_id: req.message.clone(),
+            chain_id: req.message.clone(),
+            client_id: req.client_id.clone(),
+            client_ref,
+            orig_cl_ord_id: (!req.orig_cl_ord_id.is_empty()).then(|| req.orig_cl_ord_id.clone()),
+            order_id: None,
+            account: req.account.clone(),
+            symbol: req.symbol.clone(),
+            side: req.side(),
+            price: req.price,
+            quantity: req.quantity,
+            state: OrderState::PendingNew,
+            cum_qty: 0.0,
+            leaves_qty: req.quantity,
+            avg_px: 0.0,
+            state_before_cancel: None,
+            exec_ids: Vec::new(),
+            expire_at: None,
+        }
+    }
+}
+
+/// Quantity filled by one execution report, at the price of that fill.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fill {
+    pub exec_id: String,
+    pub account: String,
+    pub symbol: String,
+    #[serde(with = "side_number")]
+    pub side: Side,
+    pub quantity: f64,
+    pub price: f64,
+}
+
+/// Change an execution report makes to the positions.
+#[derive(Debug, Clone)]
+pub enum FillUpdate {
+    /// New fill, or the corrected fill of an earlier ExecID.
+    Fill(Fill),
+    /// The broker canceled the fill of `exec_id`.
+    Bust {
+        account: String,
+        symbol: String,
+        exec_id: String,
+    },
+}
+
+#[derive(Debug)]
+pub enum Execution {
+    /// The ExecID was already applied, the report changes nothing.
+    Duplicate,
+    Applied(Option<FillUpdate>),
+}
+
+#[derive(Debug)]
+pub enum OrderAnomaly {
+    DuplicateClOrdId(String),
+    DuplicateClientRef(String),
+    /// ClOrdID counter, seen client references or journal could not be written.
+    Persistence(String),
+    UnknownOrder(String),
+    /// A cancel or replace of an order of another client.
+    OtherClient {
+        cl_ord_id: String,
+        client_id: String,
+    },
+    /// A cancel or replace naming another account, symbol or side than its order.
+    Mismatch {
+        cl_ord_id: String,
+        field: &'static str,
+    },
+    IllegalTransition {
+        cl_ord_id: String,
+        from: OrderState,
+        to: OrderState,
+    },
+    UnsupportedStatus(String, OrdStatus),
+}
+
+impl fmt::Display for OrderAnomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OrderAnomaly::DuplicateClOrdId(id) => write!(f, "duplicate ClOrdID {}", id),
+            OrderAnomaly::DuplicateClientRef(id) => {
+                write!(f, "duplicate order, client reference {} already used", id)
+            }
+            OrderAnomaly::Persistence(e) => write!(f, "cannot persist order state: {}", e),
+            OrderAnomaly::UnknownOrder(id) => write!(f, "unknown order {}", id),
+            OrderAnomaly::OtherClient {
+                cl_ord_id,
+                client_id,
+            } => write!(
+                f,
+                "order {} is not an order of client {}",
+                cl_ord_id, client_id
+            ),
+            OrderAnomaly::Mismatch { cl_ord_id, field } => {
+                write!(f, "{} differs from that of order {}", field, cl_ord_id)
+            }
+            OrderAnomaly::IllegalTransition {
+                cl_ord_id,
+                from,
+                to,
+            } => write!(
+                f,
+                "order {} illegal transition {:?} -> {:?}",
+                cl_ord_id, from, to
+            ),
+            OrderAnomaly::UnsupportedStatus(id, status) => {
+                write!(f, "order {} unsupported OrdStatus {:?}", id, status)
+            }
+        }
+    }
+}
+
+/// Order book keyed by ClOrdID, driven by the execution reports of the broker.
 pub struct OrderManager {
-    orders: HashSet<String>,
+    orders: HashMap<String, Order>,
+    /// OrderID assigned by the broker -> ClOrdID
+    by_order_id: HashMap<String, String>,
+    /// ClOrdID of a cancel request -> ClOrdID of the order it cancels
+    cancel_requests: HashMap<String, String>,
+    /// (client id, client reference) -> ClOrdID sent to the broker
+    client_refs: HashMap<(String, String), String>,
+    /// ExecID -> ClOrdID of every applied execution report
+    exec_ids: HashMap<String, String>,
+    /// Chain id -> ClOrdIDs of the chain, oldest first
+    chains: HashMap<String, Vec<String>>,
+    cl_ord_ids: ClOrdIdGenerator,
+    duplicates: DuplicateFilter,
+    journal: Arc<Journal>,
 }
 
 impl OrderManager {
-    pub fn new() -> Self {
+    pub fn new(
+        cl_ord_ids: ClOrdIdGenerator,
+        duplicates: DuplicateFilter,
+        journal: Arc<Journal>,
+    ) -> Self {
         OrderManager {
-            orders: HashSet::new(),
+            orders: HashMap::new(),
+            by_order_id: HashMap::new(),
+            cancel_requests: HashMap::new(),
+            client_refs: HashMap::new(),
+            exec_ids: HashMap::new(),
+            chains: HashMap::new(),
+            cl_ord_ids,
+            duplicates,
+            journal,
+        }
+    }
+
+    /// Rebuilds the book from the orders and cancel requests of the journal.
+    pub fn restore(&mut self, orders: Vec<Order>, cancel_requests: Vec<CancelRequest>) {
+        for mut order in orders {
+            if order.chain_id.is_empty() {
+                // journaled before chains were kept, an order is booked after the one it replaces
+                order.chain_id = order
+                    .orig_cl_ord_id
+                    .as_ref()
+                    .and_then(|orig| self.orders.get(orig))
+                    .map_or_else(|| order.cl_ord_id.clone(), |orig| orig.chain_id.clone());
+            }
+            self.chains
+                .entry(order.chain_id.clone())
+                .or_default()
+                .push(order.cl_ord_id.clone());
+            if let Some(order_id) = &order.order_id {
+                self.by_order_id
+                    .insert(order_id.clone(), order.cl_ord_id.clone());
+            }
+            if let Some(client_ref) = &order.client_ref {
+                self.client_refs.insert(
+                    (order.client_id.clone(), client_ref.clone()),
+                    order.cl_ord_id.clone(),
+                );
+            }
+            for exec_id in &order.exec_ids {
+                self.exec_ids
+                    .insert(exec_id.clone(), order.cl_ord_id.clone());
+            }
+            self.orders.insert(order.cl_ord_id.clone(), order);
+        }
+        for cancel in cancel_requests {
+            if let Some(client_ref) = cancel.client_ref {
+                self.client_refs
+                    .insert((cancel.client_id, client_ref), cancel.cl_ord_id.clone());
+            }
+            self.cancel_requests
+                .insert(cancel.cl_ord_id, cancel.orig_cl_ord_id);
+        }
+    }
+
+    /// Looks up an order by the client's reference or ClOrdID.
+    pub fn get(&self, client_id: &str, id: &str) -> Option<&Order> {
+        let cl_ord_id = self
+            .client_refs
+            .get(&(client_id.to_string(), id.to_string()))
+            .map(String::as_str)
+            .unwrap_or(id);
+        self.orders.get(cl_ord_id)
+    }
+
+    /// Orders of the replace chain of an order (named as in `get`), oldest
+    /// first, with the ClOrdID of the live one.
+    pub fn get_chain(&self, client_id: &str, id: &str) -> Option<(Vec<&Order>, String)> {
+        let order = self.get(client_id, id)?;
+        let chain = self.chains[&order.chain_id]
+            .iter()
+            .map(|cl_ord_id| &self.orders[cl_ord_id])
+            .collect();
+        Some((chain, self.live_in_chain(&order.cl_ord_id)))
+    }
+
+    /// Chain id of the order an execution report is for.
+    pub fn chain_id(&self, event: &ExecutionEvent) -> Option<String> {
+        let cl_ord_id = self
+            .resolve(
+                event.cl_ord_id.as_deref().unwrap_or(&event.order_id),
+                Some(&event.order_id),
+            )
+            .ok()?;
+        Some(self.orders[&cl_ord_id].chain_id.clone())
+    }
+
+    /// Orders not done yet, a replacement pending along with the order it replaces.
+    pub fn open_orders(&self) -> impl Iterator<Item = &Order> {
+        self.orders.values().filter(|o| !o.state.is_terminal())
+    }
+
+    /// Cancel requests for the live orders matching a mass cancel filter.
+    /// Orders with a cancel already pending are left alone.
+    pub fn mass_cancel_requests(&self, filter: &MassCancelRequest) -> Vec<RequestMessage> {
+        let mut requests: Vec<_> = self
+            .orders
+            .values()
+            .filter(|o| !o.state.is_terminal() && o.state != OrderState::PendingCancel)
+            .filter(|o| self.live_in_chain(&o.cl_ord_id) == o.cl_ord_id)
+            .filter(|o| filter.account.is_empty() || o.account == filter.account)
+            .filter(|o| filter.symbol.is_empty() || o.symbol == filter.symbol)
+            .filter(|o| filter.side() == Side::Unspecified || o.side == filter.side())
+            .filter(|o| filter.client_id.is_empty() || o.client_id == filter.client_id)
+            .map(Order::cancel_request)
+            .collect();
+        requests.sort_by(|a, b| a.orig_cl_ord_id.cmp(&b.orig_cl_ord_id));
+        requests
+    }
+
+    /// The gateway has to cancel the order at `at`.
+    pub fn schedule_expiry(
+        &mut self,
+        cl_ord_id: &str,
+        at: DateTime<Utc>,
+    ) -> Result<(), OrderAnomaly> {
+        let order = self
+            .orders
+            .get_mut(cl_ord_id)
+            .ok_or_else(|| OrderAnomaly::UnknownOrder(cl_ord_id.to_string()))?;
+        order.expire_at = Some(at.timestamp_millis());
+        self.save(cl_ord_id)
+    }
+
+    /// Cancel requests for the open orders past their expiry, an order is only
+    /// expired once.
+    pub fn take_expired(&mut self, now: DateTime<Utc>) -> Vec<RequestMessage> {
+        let now = now.timestamp_millis();
+        let expired: Vec<_> = self
+            .orders
+            .values()
+            .filter(|o| !o.state.is_terminal() && o.state != OrderState::PendingCancel)
+            .filter(|o| self.live_in_chain(&o.cl_ord_id) == o.cl_ord_id)
+            .filter(|o| o.expire_at.is_some_and(|at| at <= now))
+            .map(|o| o.cl_ord_id.clone())
+            .collect();
+        let mut requests = Vec::new();
+        for cl_ord_id in expired {
+            let order = self.orders.get_mut(&cl_ord_id).unwrap();
+            order.expire_at = None;
+            requests.push(order.cancel_request());
+            log_anomaly(self.save(&cl_ord_id));
         }
+        requests
+    }
+
+    /// Tracks a new order as `PendingNew`.
+    ///
+    /// The request `message` is kept as the client reference and replaced by
+    /// a new gateway ClOrdID, which is returned.
+    pub fn add_order(&mut self, req: &mut RequestMessage) -> Result<String, OrderAnomaly> {
+        self.book_order(req, None)
     }
 
-    pub async fn check_and_insert(&mut self, order_id: &str) -> bool {
-        if self.orders.contains(order_id) {
-            true
+    /// Moves the order named by `orig_cl_ord_id` to `PendingCancel`, the
+    /// request gets a new ClOrdID as in `add_order`. An order with a cancel
+    /// already pending is refused.
+    pub fn add_cancel(&mut self, req: &mut RequestMessage) -> Result<String, OrderAnomaly> {
+        let client_ref = self.check_client_ref(req)?;
+        self.resolve_request(req)?;
+        let orig = self.live_order(&req.orig_cl_ord_id, OrderState::PendingCancel)?;
+        if orig.state == OrderState::PendingCancel {
+            return Err(OrderAnomaly::IllegalTransition {
+                cl_ord_id: orig.cl_ord_id.clone(),
+                from: orig.state,
+                to: OrderState::PendingCancel,
+            });
+        }
+        let cl_ord_id = self.assign_cl_ord_id(req, client_ref.clone())?;
+        self.journal(&Record::CancelRequest(CancelRequest {
+            cl_ord_id: cl_ord_id.clone(),
+            orig_cl_ord_id: req.orig_cl_ord_id.clone(),
+            client_id: req.client_id.clone(),
+            client_ref,
+        }))?;
+        let order = self.orders.get_mut(&req.orig_cl_ord_id).unwrap();
+        let from = order.state;
+        transition(order, OrderState::PendingCancel)?;
+        // a replace pending keeps the state from before the replace
+        order.state_before_cancel.get_or_insert(from);
+        self.cancel_requests
+            .insert(cl_ord_id.clone(), req.orig_cl_ord_id.clone());
+        self.save(&req.orig_cl_ord_id)?;
+        Ok(cl_ord_id)
+    }
+
+    /// Tracks the replacing order as `PendingNew` and moves the live order of
+    /// the chain to `PendingReplace`, it stays live until the broker confirms
+    /// the replace.
+    pub fn add_replace(&mut self, req: &mut RequestMessage) -> Result<String, OrderAnomaly> {
+        self.resolve_request(req)?;
+        let orig = self.live_order(&req.orig_cl_ord_id, OrderState::PendingReplace)?;
+        // checked before the replacing order is booked, one cancel or replace at a time
+        if matches!(
+            orig.state,
+            OrderState::PendingCancel | OrderState::PendingReplace
+        ) || !orig.state.can_move_to(OrderState::PendingReplace)
+        {
+            return Err(OrderAnomaly::IllegalTransition {
+                cl_ord_id: orig.cl_ord_id.clone(),
+                from: orig.state,
+                to: OrderState::PendingReplace,
+            });
+        }
+        let orig = orig.clone();
+        let cl_ord_id = self.book_order(req, Some(&orig))?;
+        let order = self.orders.get_mut(&orig.cl_ord_id).unwrap();
+        transition(order, OrderState::PendingReplace)?;
+        order.state_before_cancel = Some(orig.state);
+        self.save(&orig.cl_ord_id)?;
+        Ok(cl_ord_id)
+    }
+
+    /// The request could not be sent, a new or replacing order is rejected
+    /// and the order it replaces goes back to its previous state.
+    pub fn reject(&mut self, cl_ord_id: &str) -> Result<(), OrderAnomaly> {
+        let order = self
+            .orders
+            .get_mut(cl_ord_id)
+            .ok_or_else(|| OrderAnomaly::UnknownOrder(cl_ord_id.to_string()))?;
+        transition(order, OrderState::Rejected)?;
+        let orig = order.orig_cl_ord_id.clone();
+        self.save(cl_ord_id)?;
+        match orig {
+            Some(orig) if self.orders.contains_key(&orig) => self.revert_pending(&orig),
+            _ => Ok(()),
+        }
+    }
+
+    /// The cancel or replace request `cl_ord_id` was rejected or could not be sent.
+    pub fn cancel_rejected(&mut self, cl_ord_id: &str) -> Result<(), OrderAnomaly> {
+        let replacing = self
+            .orders
+            .get(cl_ord_id)
+            .is_some_and(|o| o.state == OrderState::PendingNew && o.orig_cl_ord_id.is_some());
+        if replacing {
+            return self.reject(cl_ord_id);
+        }
+        let cl_ord_id = self.resolve(cl_ord_id, None)?;
+        self.revert_pending(&cl_ord_id)
+    }
+
+    /// Moves an order with a pending cancel or replace back to its previous state.
+    fn revert_pending(&mut self, cl_ord_id: &str) -> Result<(), OrderAnomaly> {
+        let order = self.orders.get_mut(cl_ord_id).unwrap();
+        if !matches!(
+            order.state,
+            OrderState::PendingCancel | OrderState::PendingReplace
+        ) {
+            return Ok(());
+        }
+        let previous = order.state_before_cancel.take().unwrap_or(OrderState::New);
+        transition(order, previous)?;
+        self.save(cl_ord_id)
+    }
+
+    /// Applies an execution report to the order lifecycle and returns what
+    /// it changes in the positions.
+    ///
+    /// A report whose ExecID was already applied changes nothing. A cancel or
+    /// correct (ExecTransType) busts or replaces the fill of its ExecRefID and
+    /// puts the order in the reported status.
+    pub fn apply_execution(&mut self, event: &ExecutionEvent) -> Result<Execution, OrderAnomaly> {
+        if let Some(cl_ord_id) = self.exec_ids.get(&event.exec_id) {
+            if !event.poss_dup {
+                warn!(
+                    "ExecID {} of order {} received again without PossDupFlag",
+                    event.exec_id, cl_ord_id
+                );
+            }
+            return Ok(Execution::Duplicate);
+        }
+        let cl_ord_id = self.resolve(
+            event.cl_ord_id.as_deref().unwrap_or(&event.order_id),
+            Some(&event.order_id),
+        )?;
+        let to = OrderState::from_ord_status(event.ord_status)
+            .ok_or_else(|| OrderAnomaly::UnsupportedStatus(cl_ord_id.clone(), event.ord_status))?;
+        let trans_type = event.exec_trans_type.unwrap_or(ExecTransType::New);
+        let correction = matches!(trans_type, ExecTransType::Cancel | ExecTransType::Correct);
+
+        // the replacing order is confirmed, the one it replaces is done
+        let replaced = self
+            .orders
+            .get(&cl_ord_id)
+            .and_then(|o| o.orig_cl_ord_id.clone())
+            .filter(|_| event.exec_type == ExecType::Replaced && !correction);
+        if let Some(orig) = replaced {
+            let orig_order = self
+                .orders
+                .get_mut(&orig)
+                .ok_or_else(|| OrderAnomaly::UnknownOrder(orig.clone()))?;
+            transition(orig_order, OrderState::Replaced)?;
+            self.save(&orig)?;
+        }
+
+        self.by_order_id
+            .insert(event.order_id.clone(), cl_ord_id.clone());
+        let order = self.orders.get_mut(&cl_ord_id).unwrap();
+        if correction {
+            // a busted or corrected fill may take the order out of a terminal state
+            order.state = to;
+        } else if to == OrderState::PendingReplace && order.state == OrderState::PendingNew {
+            // the broker acknowledges the replace, the replaced order is already PendingReplace
         } else {
-            self.orders.insert(order_id.to_string()); // 插入 String（必须将 &str 转为 String）
-            false
+            transition(order, to)?;
         }
+        let update = match trans_type {
+            ExecTransType::New => {
+                let filled = event.cum_qty - order.cum_qty;
+                (filled > 0.0).then(|| {
+                    FillUpdate::Fill(Fill {
+                        exec_id: event.exec_id.clone(),
+                        account: order.account.clone(),
+                        symbol: order.symbol.clone(),
+                        side: order.side,
+                        quantity: filled,
+                        price: event.last_px.unwrap_or(
+                            (event.cum_qty * event.avg_px - order.cum_qty * order.avg_px) / filled,
+                        ),
+                    })
+                })
+            }
+            ExecTransType::Cancel => event.exec_ref_id.clone().map(|exec_id| FillUpdate::Bust {
+                account: order.account.clone(),
+                symbol: order.symbol.clone(),
+                exec_id,
+            }),
+            ExecTransType::Correct => {
+                match (&event.exec_ref_id, event.last_shares, event.last_px) {
+                    (Some(exec_id), Some(quantity), Some(price)) => Some(FillUpdate::Fill(Fill {
+                        exec_id: exec_id.clone(),
+                        account: order.account.clone(),
+                        symbol: order.symbol.clone(),
+                        side: order.side,
+                        quantity,
+                        price,
+                    })),
+                    _ => None,
+                }
+            }
+            ExecTransType::Status => None,
+        };
+        if correction && update.is_none() {
+            warn!(
+                "{:?} ExecID {} without ExecRefID, LastShares or LastPx, positions unchanged",
+                trans_type, event.exec_id
+            );
+        }
+        order.exec_ids.push(event.exec_id.clone());
+        self.exec_ids
+            .insert(event.exec_id.clone(), cl_ord_id.clone());
+        order.order_id = Some(event.order_id.clone());
+        order.cum_qty = event.cum_qty;
+        order.leaves_qty = event.leaves_qty;
+        order.avg_px = event.avg_px;
+        if !matches!(to, OrderState::PendingCancel | OrderState::PendingReplace) {
+            order.state_before_cancel = None;
+        }
+        self.save(&cl_ord_id)?;
+        Ok(Execution::Applied(update))
+    }
+
+    /// Books a new order, or the replacing order of `replaces`.
+    fn book_order(
+        &mut self,
+        req: &mut RequestMessage,
+        replaces: Option<&Order>,
+    ) -> Result<String, OrderAnomaly> {
+        let client_ref = self.check_client_ref(req)?;
+        let cl_ord_id = self.assign_cl_ord_id(req, client_ref.clone())?;
+        if self.orders.contains_key(&cl_ord_id) {
+            return Err(OrderAnomaly::DuplicateClOrdId(cl_ord_id));
+        }
+        let mut order = Order::from_request(req, client_ref);
+        if let Some(orig) = replaces {
+            // CumQty of the replacing order goes on from the original one
+            order.chain_id = orig.chain_id.clone();
+            order.cum_qty = orig.cum_qty;
+            order.avg_px = orig.avg_px;
+            order.leaves_qty = (order.quantity - orig.cum_qty).max(0.0);
+        }
+        // on disk before it is sent
+        self.journal(&Record::Order(order.clone()))?;
+        self.chains
+            .entry(order.chain_id.clone())
+            .or_default()
+            .push(cl_ord_id.clone());
+        self.orders.insert(cl_ord_id.clone(), order);
+        Ok(cl_ord_id)
+    }
+
+    fn journal(&self, record: &Record) -> Result<(), OrderAnomaly> {
+        self.journal
+            .append(record)
+            .map_err(|e| OrderAnomaly::Persistence(e.to_string()))
+    }
+
+    fn save(&self, cl_ord_id: &str) -> Result<(), OrderAnomaly> {
+        self.journal(&Record::Order(self.orders[cl_ord_id].clone()))
+    }
+
+    /// The client reference of a request, refused if the duplicate check of
+    /// the client has already seen it.
+    fn check_client_ref(&self, req: &RequestMessage) -> Result<Option<String>, OrderAnomaly> {
+        if req.message.is_empty() {
+            return Ok(None);
+        }
+        if self.duplicates.is_duplicate(&req.client_id, &req.message) {
+            return Err(OrderAnomaly::DuplicateClientRef(req.message.clone()));
+        }
+        Ok(Some(req.message.clone()))
+    }
+
+    /// A new gateway id, from the ClOrdID counter.
+    pub fn next_cl_ord_id(&mut self) -> Result<String, OrderAnomaly> {
+        self.cl_ord_ids
+            .next_id()
+            .map_err(|e| OrderAnomaly::Persistence(e.to_string()))
+    }
+
+    fn assign_cl_ord_id(
+        &mut self,
+        req: &mut RequestMessage,
+        client_ref: Option<String>,
+    ) -> Result<String, OrderAnomaly> {
+        let cl_ord_id = self.next_cl_ord_id()?;
+        if let Some(client_ref) = client_ref {
+            self.duplicates
+                .record(&req.client_id, &client_ref)
+                .map_err(|e| OrderAnomaly::Persistence(e.to_string()))?;
+            self.client_refs
+                .insert((req.client_id.clone(), client_ref), cl_ord_id.clone());
+        }
+        req.message = cl_ord_id.clone();
+        Ok(cl_ord_id)
+    }
+
+    /// Points the `orig_cl_ord_id` of a cancel or replace, a ClOrdID or
+    /// client reference, at the live order of its chain and takes the client,
+    /// account, symbol and side from that order, so the checks of the request
+    /// apply to the order it changes. An order of another client, or fields
+    /// set to other values, are refused. A new order is left as it is.
+    pub fn resolve_request(&self, req: &mut RequestMessage) -> Result<(), OrderAnomaly> {
+        if req.orig_cl_ord_id.is_empty() {
+            return Ok(());
+        }
+        let key = (req.client_id.clone(), req.orig_cl_ord_id.clone());
+        if let Some(cl_ord_id) = self.client_refs.get(&key) {
+            req.orig_cl_ord_id = cl_ord_id.clone();
+        }
+        req.orig_cl_ord_id = self.live_in_chain(&req.orig_cl_ord_id);
+        let orig = self
+            .orders
+            .get(&req.orig_cl_ord_id)
+            .ok_or_else(|| OrderAnomaly::UnknownOrder(req.orig_cl_ord_id.clone()))?;
+        if orig.client_id != req.client_id {
+            return Err(OrderAnomaly::OtherClient {
+                cl_ord_id: orig.cl_ord_id.clone(),
+                client_id: req.client_id.clone(),
+            });
+        }
+        let mismatch = if !req.account.is_empty() && req.account != orig.account {
+            Some("account")
+        } else if !req.symbol.is_empty() && req.symbol != orig.symbol {
+            Some("symbol")
+        } else if req.side() != Side::Unspecified && req.side() != orig.side {
+            Some("side")
+        } else {
+            None
+        };
+        if let Some(field) = mismatch {
+            return Err(OrderAnomaly::Mismatch {
+                cl_ord_id: orig.cl_ord_id.clone(),
+                field,
+            });
+        }
+        req.account = orig.account.clone();
+        req.symbol = orig.symbol.clone();
+        req.side = orig.side as i32;
+        Ok(())
+    }
+
+    /// The live order of the chain of `cl_ord_id`: the latest one whose
+    /// predecessor the broker confirmed as replaced. An unknown ClOrdID is
+    /// returned as is.
+    pub fn live_in_chain(&self, cl_ord_id: &str) -> String {
+        let Some(chain) = self
+            .orders
+            .get(cl_ord_id)
+            .and_then(|order| self.chains.get(&order.chain_id))
+        else {
+            return cl_ord_id.to_string();
+        };
+        let mut live = &chain[0];
+        for next in &chain[1..] {
+            let replaced = self.orders[next]
+                .orig_cl_ord_id
+                .as_ref()
+                .and_then(|orig| self.orders.get(orig))
+                .is_some_and(|orig| orig.state == OrderState::Replaced);
+            if replaced {
+                live = next;
+            }
+        }
+        live.clone()
+    }
+
+    /// Finds the tracked order of a ClOrdID, a cancel request ClOrdID or an OrderID.
+    fn resolve(&self, cl_ord_id: &str, order_id: Option<&str>) -> Result<String, OrderAnomaly> {
+        if self.orders.contains_key(cl_ord_id) {
+            return Ok(cl_ord_id.to_string());
+        }
+        if let Some(target) = self.cancel_requests.get(cl_ord_id) {
+            return Ok(target.clone());
+        }
+        order_id
+            .and_then(|id| self.by_order_id.get(id))
+            .cloned()
+            .ok_or_else(|| OrderAnomaly::UnknownOrder(cl_ord_id.to_string()))
+    }
+
+    fn live_order(&mut self, cl_ord_id: &str, to: OrderState) -> Result<&mut Order, OrderAnomaly> {
+        let order = self
+            .orders
+            .get_mut(cl_ord_id)
+            .ok_or_else(|| OrderAnomaly::UnknownOrder(cl_ord_id.to_string()))?;
+        if order.state.is_terminal() {
+            return Err(OrderAnomaly::IllegalTransition {
+                cl_ord_id: cl_ord_id.to_string(),
+                from: order.state,
+                to,
+            });
+        }
+        Ok(order)
+    }
+}
+
+/// Logs the anomaly of an order book update, the book itself is left unchanged.
+pub fn log_anomaly<T>(result: Result<T, OrderAnomaly>) -> Option<T> {
+    result.map_err(|e| error!("order anomaly: {}", e)).ok()
+}
+
+fn transition(order: &mut Order, to: OrderState) -> Result<(), OrderAnomaly> {
+    if !order.state.can_move_to(to) {
+        return Err(OrderAnomaly::IllegalTransition {
+            cl_ord_id: order.cl_ord_id.clone(),
+            from: order.state,
+            to,
+        });
+    }
+    order.state = to;
+    Ok(())
+}
+
+/// Journals the proto `Side` by its number.
+mod side_number {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use crate::server::fantasy::Side;
+
+    pub fn serialize<S: Serializer>(side: &Side, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_i32(*side as i32)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Side, D::Error> {
+        Side::try_from(i32::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use fantasy_fix42::field_types::Side as FixSide;
+
+    use super::*;
+    use crate::cfg::DuplicateCheckCfg;
+    use crate::event_log::EventLog;
+
+    pub(crate) fn book() -> OrderManager {
+        OrderManager::new(
+            ClOrdIdGenerator::load("T", "").unwrap(),
+            DuplicateFilter::load(&DuplicateCheckCfg::default()).unwrap(),
+            Arc::new(
+                Journal::open(
+                    "",
+                    &Default::default(),
+                    &mut EventLog::new(&Default::default()).unwrap(),
+                )
+                .unwrap()
+                .0,
+            ),
+        )
+    }
+
+    pub(crate) fn order(om: &mut OrderManager, client_ref: &str, quantity: f64) -> String {
+        let mut req = RequestMessage {
+            message: client_ref.to_string(),
+            client_id: "c1".to_string(),
+            account: "acc".to_string(),
+            symbol: "USDJPY".to_string(),
+            side: Side::Buy as i32,
+            price: 150.0,
+            quantity,
+            ..Default::default()
+        };
+        om.add_order(&mut req).unwrap()
+    }
+
+    pub(crate) fn report(
+        cl_ord_id: &str,
+        exec_id: &str,
+        exec_type: ExecType,
+        ord_status: OrdStatus,
+        cum_qty: f64,
+    ) -> ExecutionEvent {
+        ExecutionEvent {
+            order_id: format!("B-{cl_ord_id}"),
+            cl_ord_id: Some(cl_ord_id.to_string()),
+            orig_cl_ord_id: None,
+            chain_id: None,
+            exec_id: exec_id.to_string(),
+            exec_trans_type: None,
+            exec_ref_id: None,
+            exec_type,
+            ord_status,
+            symbol: "USDJPY".to_string(),
+            side: FixSide::Buy,
+            leaves_qty: 0.0,
+            cum_qty,
+            avg_px: 150.0,
+            last_shares: None,
+            last_px: None,
+            text: None,
+            poss_dup: false,
+            custom_tags: Default::default(),
+        }
+    }
+
+    fn state(om: &OrderManager, cl_ord_id: &str) -> OrderState {
+        om.get("", cl_ord_id).unwrap().state
+    }
+
+    #[test]
+    fn transitions() {
+        use OrderState::*;
+        assert!(PendingNew.can_move_to(New));
+        assert!(!PendingNew.can_move_to(PendingReplace));
+        assert!(New.can_move_to(PendingCancel));
+        assert!(PendingCancel.can_move_to(PendingCancel));
+        assert!(PendingCancel.can_move_to(PartiallyFilled));
+        assert!(!Filled.can_move_to(Filled));
+        assert!(!Canceled.can_move_to(New));
+        assert!(!Rejected.can_move_to(PendingCancel));
+    }
+
+    #[test]
+    fn rejected_cancel_restores_the_state_before_it() {
+        let mut om = book();
+        let id = order(&mut om, "r1", 100.0);
+        om.apply_execution(&report(&id, "e1", ExecType::New, OrdStatus::New, 0.0))
+            .unwrap();
+        om.apply_execution(&report(
+            &id,
+            "e2",
+            ExecType::PartialFill,
+            OrdStatus::PartiallyFilled,
+            40.0,
+        ))
+        .unwrap();
+
+        let mut cancel = om.get("", &id).unwrap().cancel_request();
+        let cancel_id = om.add_cancel(&mut cancel).unwrap();
+        assert_eq!(state(&om, &id), OrderState::PendingCancel);
+        // a second cancel while the first one is pending is refused
+        let mut again = om.get("", &id).unwrap().cancel_request();
+        assert!(matches!(
+            om.add_cancel(&mut again),
+            Err(OrderAnomaly::IllegalTransition { .. })
+        ));
+
+        om.cancel_rejected(&cancel_id).unwrap();
+        assert_eq!(state(&om, &id), OrderState::PartiallyFilled);
+    }
+
+    #[test]
+    fn confirmed_replace_moves_the_chain_to_the_new_order() {
+        let mut om = book();
+        let id = order(&mut om, "r1", 100.0);
+        om.apply_execution(&report(&id, "e1", ExecType::New, OrdStatus::New, 0.0))
+            .unwrap();
+
+        let mut replace = RequestMessage {
+            message: "r2".to_string(),
+            orig_cl_ord_id: "r1".to_string(),
+            client_id: "c1".to_string(),
+            side: Side::Buy as i32,
+            price: 151.0,
+            quantity: 200.0,
+            ..Default::default()
+        };
+        let new_id = om.add_replace(&mut replace).unwrap();
+        assert_eq!(state(&om, &id), OrderState::PendingReplace);
+        assert_eq!(state(&om, &new_id), OrderState::PendingNew);
+        // booked under the account and symbol of the order it replaces
+        let replacing = om.get("", &new_id).unwrap();
+        assert_eq!(
+            (replacing.account.as_str(), replacing.symbol.as_str()),
+            ("acc", "USDJPY")
+        );
+        assert_eq!(om.live_in_chain(&new_id), id);
+
+        om.apply_execution(&report(
+            &new_id,
+            "e2",
+            ExecType::Replaced,
+            OrdStatus::New,
+            0.0,
+        ))
+        .unwrap();
+        assert_eq!(state(&om, &id), OrderState::Replaced);
+        assert_eq!(state(&om, &new_id), OrderState::New);
+        assert_eq!(om.live_in_chain(&id), new_id);
+        // a cancel by the first reference goes to the live order
+        let mut cancel = RequestMessage {
+            orig_cl_ord_id: "r1".to_string(),
+            client_id: "c1".to_string(),
+            ..Default::default()
+        };
+        om.add_cancel(&mut cancel).unwrap();
+        assert_eq!(cancel.orig_cl_ord_id, new_id);
+    }
+
+    #[test]
+    fn rejected_replace_restores_the_original_order() {
+        let mut om = book();
+        let id = order(&mut om, "r1", 100.0);
+        om.apply_execution(&report(&id, "e1", ExecType::New, OrdStatus::New, 0.0))
+            .unwrap();
+        let mut replace = RequestMessage {
+            message: "r2".to_string(),
+            orig_cl_ord_id: id.clone(),
+            client_id: "c1".to_string(),
+            quantity: 50.0,
+            ..Default::default()
+        };
+        let new_id = om.add_replace(&mut replace).unwrap();
+
+        om.cancel_rejected(&new_id).unwrap();
+        assert_eq!(state(&om, &new_id), OrderState::Rejected);
+        assert_eq!(state(&om, &id), OrderState::New);
+        assert_eq!(om.live_in_chain(&new_id), id);
+    }
+
+    #[test]
+    fn replace_of_a_pending_new_order_books_nothing() {
+        let mut om = book();
+        let id = order(&mut om, "r1", 100.0);
+        let mut replace = RequestMessage {
+            message: "r2".to_string(),
+            orig_cl_ord_id: id.clone(),
+            client_id: "c1".to_string(),
+            quantity: 50.0,
+            ..Default::default()
+        };
+        assert!(matches!(
+            om.add_replace(&mut replace),
+            Err(OrderAnomaly::IllegalTransition { .. })
+        ));
+        assert_eq!(state(&om, &id), OrderState::PendingNew);
+        assert_eq!(om.live_in_chain(&id), id);
+        assert!(om.get("c1", "r2").is_none());
+    }
+
+    #[test]
+    fn cancel_or_replace_of_another_order_refused() {
+        let mut om = book();
+        let id = order(&mut om, "r1", 100.0);
+        om.apply_execution(&report(&id, "e1", ExecType::New, OrdStatus::New, 0.0))
+            .unwrap();
+        let mut other_client = RequestMessage {
+            orig_cl_ord_id: id.clone(),
+            client_id: "c2".to_string(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            om.add_cancel(&mut other_client),
+            Err(OrderAnomaly::OtherClient { .. })
+        ));
+        let mut other_symbol = RequestMessage {
+            message: "r2".to_string(),
+            orig_cl_ord_id: "r1".to_string(),
+            client_id: "c1".to_string(),
+            symbol: "EURUSD".to_string(),
+            quantity: 50.0,
+            ..Default::default()
+        };
+        assert!(matches!(
+            om.add_replace(&mut other_symbol),
+            Err(OrderAnomaly::Mismatch {
+                field: "symbol",
+                ..
+            })
+        ));
+        assert_eq!(state(&om, &id), OrderState::New);
     }
 }
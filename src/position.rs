@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+
+use crate::order_manager::{Fill, FillUpdate};
+use crate::server::fantasy::{PositionReport, Side};
+
+/// Net position of an account in one symbol, quantity is negative when short.
+#[derive(Debug, Clone, Default)]
+pub struct Position {
+    pub account: String,
+    pub symbol: String,
+    pub quantity: f64,
+    /// Average price of the open quantity.
+    pub avg_cost: f64,
+    pub realized_pnl: f64,
+    /// Sequence number of the last update, see `PositionBook::updated_since`.
+    pub seq: u64,
+}
+
+impl Position {
+    fn apply_fill(&mut self, fill: &Fill) {
+        match fill.side {
+            Side::Sell => self.apply(-fill.quantity, fill.price),
+            _ => self.apply(fill.quantity, fill.price),
+        }
+    }
+
+    fn apply(&mut self, quantity: f64, price: f64) {
+        let signed = quantity.copysign(self.quantity);
+        if self.quantity == 0.0 || signed == quantity {
+            // opening or adding, the average cost moves
+            let total = self.quantity.abs() + quantity.abs();
+            self.avg_cost = (self.quantity.abs() * self.avg_cost + quantity.abs() * price) / total;
+            self.quantity += quantity;
+            return;
+        }
+        // reducing, the closed quantity realizes P&L against the average cost
+        let closed = quantity.abs().min(self.quantity.abs());
+        self.realized_pnl += closed * (price - self.avg_cost) * self.quantity.signum();
+        self.quantity += quantity;
+        if self.quantity.abs() < 1e-9 {
+            self.quantity = 0.0;
+            self.avg_cost = 0.0;
+        } else if self.quantity.signum() == quantity.signum() {
+            // crossed through flat, the remainder is opened at the fill price
+            self.avg_cost = price;
+        }
+    }
+
+    pub fn unrealized_pnl(&self, mark_price: Option<f64>) -> f64 {
+        mark_price.map_or(0.0, |mark| (mark - self.avg_cost) * self.quantity)
+    }
+}
+
+/// Positions by account and symbol, built from the fills of execution reports.
+#[derive(Debug, Default)]
+pub struct PositionBook {
+    positions: HashMap<(String, String), Position>,
+    /// Fills of each position in arrival order, replayed when one is busted or corrected.
+    fills: HashMap<(String, String), Vec<Fill>>,
+    marks: HashMap<String, f64>,
+    seq: u64,
+}
+
+impl PositionBook {
+    pub fn new() -> Self {
+        PositionBook::default()
+    }
+
+    pub fn apply(&mut self, update: &FillUpdate) {
+        match update {
+            FillUpdate::Fill(fill) => self.apply_fill(fill),
+            FillUpdate::Bust {
+                account,
+                symbol,
+                exec_id,
+            } => self.bust_fill(account, symbol, exec_id),
+        }
+    }
+
+    /// Books a fill, a fill with the ExecID of an earlier one corrects it.
+    pub fn apply_fill(&mut self, fill: &Fill) {
+        let key = (fill.account.clone(), fill.symbol.clone());
+        let fills = self.fills.entry(key.clone()).or_default();
+        match fills.iter_mut().find(|f| f.exec_id == fill.exec_id) {
+            Some(earlier) => {
+                *earlier = fill.clone();
+                self.rebuild(key);
+            }
+            None => {
+                fills.push(fill.clone());
+                self.seq += 1;
+                let position = self.positions.entry(key).or_insert_with(|| Position {
+                    account: fill.account.clone(),
+                    symbol: fill.symbol.clone(),
+                    ..Default::default()
+                });
+                position.apply_fill(fill);
+                position.seq = self.seq;
+            }
+        }
+    }
+
+    pub fn bust_fill(&mut self, account: &str, symbol: &str, exec_id: &str) {
+        let key = (account.to_string(), symbol.to_string());
+        let Some(fills) = self.fills.get_mut(&key) else {
+            return;
+        };
+        let before = fills.len();
+        fills.retain(|f| f.exec_id != exec_id);
+        if fills.len() < before {
+            self.rebuild(key);
+        }
+    }
+
+    /// Recomputes a position from its fills, so P&L is as if a busted or
+    /// corrected fill had never been booked.
+    fn rebuild(&mut self, key: (String, String)) {
+        self.seq += 1;
+        let mut position = Position {
+            account: key.0.clone(),
+            symbol: key.1.clone(),
+            seq: self.seq,
+            ..Default::default()
+        };
+        for fill in self.fills.get(&key).into_iter().flatten() {
+            position.apply_fill(fill);
+        }
+        self.positions.insert(key, position);
+    }
+
+    /// Sets the price unrealized P&L of `symbol` is computed against.
+    pub fn set_mark(&mut self, symbol: &str, price: f64) {
+        self.marks.insert(symbol.to_string(), price);
+        self.seq += 1;
+        for position in self.positions.values_mut() {
+            if position.symbol == symbol {
+                position.seq = self.seq;
+            }
+        }
+    }
+
+    pub fn mark_price(&self, symbol: &str) -> Option<f64> {
+        self.marks.get(symbol).copied()
+    }
+
+    /// Positions matching `account` and `symbol`, an empty filter matches all.
+    pub fn query(&self, account: &str, symbol: &str) -> Vec<PositionReport> {
+        self.updated_since(account, symbol, 0)
+    }
+
+    /// Positions matching the filter updated after sequence number `seq`.
+    pub fn updated_since(&self, account: &str, symbol: &str, seq: u64) -> Vec<PositionReport> {
+        let mut reports: Vec<_> = self
+            .positions
+            .values()
+            .filter(|p| p.seq > seq)
+            .filter(|p| account.is_empty() || p.account == account)
+            .filter(|p| symbol.is_empty() || p.symbol == symbol)
+            .map(|p| self.report(p))
+            .collect();
+        reports.sort_by_key(|r| r.seq);
+        reports
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    fn report(&self, position: &Position) -> PositionReport {
+        let mark_price = self.marks.get(&position.symbol).copied();
+        PositionReport {
+            account: position.account.clone(),
+            symbol: position.symbol.clone(),
+            quantity: position.quantity,
+            avg_cost: position.avg_cost,
+            realized_pnl: position.realized_pnl,
+            unrealized_pnl: position.unrealized_pnl(mark_price),
+            mark_price: mark_price.unwrap_or_default(),
+            seq: position.seq,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fill(exec_id: &str, side: Side, quantity: f64, price: f64) -> Fill {
+        Fill {
+            exec_id: exec_id.to_string(),
+            account: "acc".to_string(),
+            symbol: "USDJPY".to_string(),
+            side,
+            quantity,
+            price,
+        }
+    }
+
+    fn position(book: &PositionBook) -> PositionReport {
+        book.query("acc", "USDJPY").remove(0)
+    }
+
+    #[test]
+    fn average_cost_and_pnl() {
+        let mut book = PositionBook::new();
+        book.apply_fill(&fill("e1", Side::Buy, 100.0, 10.0));
+        book.apply_fill(&fill("e2", Side::Buy, 100.0, 12.0));
+        let p = position(&book);
+        assert_eq!((p.quantity, p.avg_cost, p.realized_pnl), (200.0, 11.0, 0.0));
+
+        // reducing realizes against the average cost, which does not move
+        book.apply_fill(&fill("e3", Side::Sell, 150.0, 13.0));
+        let p = position(&book);
+        assert_eq!(
+            (p.quantity, p.avg_cost, p.realized_pnl),
+            (50.0, 11.0, 300.0)
+        );
+
+        // crossing through flat opens the rest at the fill price
+        book.apply_fill(&fill("e4", Side::Sell, 100.0, 9.0));
+        let p = position(&book);
+        assert_eq!(
+            (p.quantity, p.avg_cost, p.realized_pnl),
+            (-50.0, 9.0, 200.0)
+        );
+
+        book.set_mark("USDJPY", 8.0);
+        assert_eq!(position(&book).unrealized_pnl, 50.0);
+    }
+
+    #[test]
+    fn bust_and_correction_rebuild_the_position() {
+        let mut book = PositionBook::new();
+        book.apply_fill(&fill("e1", Side::Buy, 100.0, 10.0));
+        book.apply_fill(&fill("e2", Side::Sell, 100.0, 12.0));
+        assert_eq!(position(&book).realized_pnl, 200.0);
+
+        book.apply_fill(&fill("e2", Side::Sell, 100.0, 11.0));
+        assert_eq!(position(&book).realized_pnl, 100.0);
+
+        book.bust_fill("acc", "USDJPY", "e2");
+        let p = position(&book);
+        assert_eq!((p.quantity, p.avg_cost, p.realized_pnl), (100.0, 10.0, 0.0));
+    }
+}
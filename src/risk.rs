@@ -0,0 +1,287 @@
+use std::fmt;
+
+use crate::cfg::{RiskCfg, RiskLimits};
+use crate::server::fantasy::{OrdType, RequestMessage};
+
+/// Why an order failed the pre-trade checks, `scope` names the limits it broke
+/// (`default`, `account <name>` or `symbol <name>`).
+#[derive(Debug)]
+pub enum RiskReject {
+    MaxOrderQty {
+        scope: String,
+        quantity: f64,
+        limit: f64,
+    },
+    MaxNotional {
+        scope: String,
+        notional: f64,
+        limit: f64,
+    },
+    /// Notional or added exposure of a market order without a mark price for the symbol.
+    NoReferencePrice(String),
+    PriceCollar {
+        scope: String,
+        price: f64,
+        reference: f64,
+        pct: f64,
+    },
+    OrdTypeNotAllowed {
+        scope: String,
+        ord_type: String,
+    },
+    CreditLimit {
+        account: String,
+        exposure: f64,
+        added: f64,
+        limit: f64,
+    },
+}
+
+impl fmt::Display for RiskReject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RiskReject::MaxOrderQty {
+                scope,
+                quantity,
+                limit,
+            } => write!(
+                f,
+                "quantity {} above max order quantity {} ({})",
+                quantity, limit, scope
+            ),
+            RiskReject::MaxNotional {
+                scope,
+                notional,
+                limit,
+            } => write!(
+                f,
+                "notional {} above max notional {} ({})",
+                notional, limit, scope
+            ),
+            RiskReject::NoReferencePrice(symbol) => {
+                write!(f, "no reference price for {}", symbol)
+            }
+            RiskReject::PriceCollar {
+                scope,
+                price,
+                reference,
+                pct,
+            } => write!(
+                f,
+                "price {} more than {}% away from reference price {} ({})",
+                price, pct, reference, scope
+            ),
+            RiskReject::OrdTypeNotAllowed { scope, ord_type } => {
+                write!(f, "order type {} not allowed ({})", ord_type, scope)
+            }
+            RiskReject::CreditLimit {
+                account,
+                exposure,
+                added,
+                limit,
+            } => write!(
+                f,
+                "exposure {} plus {} above credit limit {} of account {}",
+                exposure, added, limit, account
+            ),
+        }
+    }
+}
+
+/// Pre-trade checks of new and replacing orders, before anything is booked or
+/// sent to the broker.
+#[derive(Debug, Clone)]
+pub struct RiskCheck {
+    cfg: RiskCfg,
+}
+
+impl RiskCheck {
+    pub fn new(cfg: &RiskCfg) -> Self {
+        RiskCheck { cfg: cfg.clone() }
+    }
+
+    /// Checks `req` against every limit that applies to it. The reference
+    /// price is the mark price of the symbol.
+    pub fn check(&self, req: &RequestMessage, reference: Option<f64>) -> Result<(), RiskReject> {
+        let scopes = [
+            Some(("default".to_string(), &self.cfg.default)),
+            self.cfg
+                .accounts
+                .get(&req.account)
+                .map(|limits| (format!("account {}", req.account), limits)),
+            self.cfg
+                .symbols
+                .get(&req.symbol)
+                .map(|limits| (format!("symbol {}", req.symbol), limits)),
+        ];
+        for (scope, limits) in scopes.into_iter().flatten() {
+            check_limits(scope, limits, req, reference)?;
+        }
+        Ok(())
+    }
+
+    pub fn credit_limit(&self, account: &str) -> Option<f64> {
+        let credit = &self.cfg.credit;
+        credit.accounts.get(account).copied().or(credit.default)
+    }
+
+    /// Checks that `added` exposure keeps `account` within its credit limit,
+    /// `added` is `None` when it cannot be valued.
+    pub fn check_credit(
+        &self,
+        req: &RequestMessage,
+        exposure: f64,
+        added: Option<f64>,
+    ) -> Result<(), RiskReject> {
+        let Some(limit) = self.credit_limit(&req.account) else {
+            return Ok(());
+        };
+        let added = added.ok_or_else(|| RiskReject::NoReferencePrice(req.symbol.clone()))?;
+        if added > 0.0 && exposure + added > limit {
+            return Err(RiskReject::CreditLimit {
+                account: req.account.clone(),
+                exposure,
+                added,
+                limit,
+            });
+        }
+        Ok(())
+    }
+}
+
+fn check_limits(
+    scope: String,
+    limits: &RiskLimits,
+    req: &RequestMessage,
+    reference: Option<f64>,
+) -> Result<(), RiskReject> {
+    let market = req.ord_type() == OrdType::Market;
+    if let Some(allowed) = &limits.allowed_ord_types {
+        let ord_type = if market { "Market" } else { "Limit" };
+        if !allowed.iter().any(|t| t.eq_ignore_ascii_case(ord_type)) {
+            return Err(RiskReject::OrdTypeNotAllowed {
+                scope,
+                ord_type: ord_type.to_string(),
+            });
+        }
+    }
+    if let Some(limit) = limits.max_order_qty.filter(|limit| req.quantity > *limit) {
+        return Err(RiskReject::MaxOrderQty {
+            scope,
+            quantity: req.quantity,
+            limit,
+        });
+    }
+    if let Some(limit) = limits.max_notional {
+        let price = if market { reference } else { Some(req.price) };
+        let price = price.ok_or_else(|| RiskReject::NoReferencePrice(req.symbol.clone()))?;
+        let notional = req.quantity * price;
+        if notional > limit {
+            return Err(RiskReject::MaxNotional {
+                scope,
+                notional,
+                limit,
+            });
+        }
+    }
+    // a market order has no price to collar, and without a mark price there is nothing to compare to
+    let collar = limits
+        .price_collar_pct
+        .zip(reference)
+        .filter(|&(pct, reference)| {
+            !market && (req.price - reference).abs() > reference.abs() * pct / 100.0
+        });
+    if let Some((pct, reference)) = collar {
+        return Err(RiskReject::PriceCollar {
+            scope,
+            price: req.price,
+            reference,
+            pct,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::fantasy::Side;
+
+    fn risk(limits: RiskLimits) -> RiskCheck {
+        RiskCheck::new(&RiskCfg {
+            default: limits,
+            ..Default::default()
+        })
+    }
+
+    fn request(ord_type: OrdType, quantity: f64, price: f64) -> RequestMessage {
+        RequestMessage {
+            account: "acc".to_string(),
+            symbol: "USDJPY".to_string(),
+            side: Side::Buy as i32,
+            ord_type: ord_type as i32,
+            quantity,
+            price,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn collar_around_the_mark_price() {
+        let risk = risk(RiskLimits {
+            price_collar_pct: Some(1.0),
+            ..Default::default()
+        });
+        let mark = Some(150.0);
+        assert!(
+            risk.check(&request(OrdType::Limit, 1.0, 151.5), mark)
+                .is_ok()
+        );
+        assert!(
+            risk.check(&request(OrdType::Limit, 1.0, 148.5), mark)
+                .is_ok()
+        );
+        assert!(matches!(
+            risk.check(&request(OrdType::Limit, 1.0, 151.6), mark),
+            Err(RiskReject::PriceCollar { reference, .. }) if reference == 150.0
+        ));
+        assert!(matches!(
+            risk.check(&request(OrdType::Limit, 1.0, 148.4), mark),
+            Err(RiskReject::PriceCollar { .. })
+        ));
+        // nothing to compare to, and no price to collar
+        assert!(
+            risk.check(&request(OrdType::Limit, 1.0, 999.0), None)
+                .is_ok()
+        );
+        assert!(
+            risk.check(&request(OrdType::Market, 1.0, 0.0), mark)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn notional_of_a_market_order_needs_a_mark_price() {
+        let risk = risk(RiskLimits {
+            max_notional: Some(1_500_000.0),
+            ..Default::default()
+        });
+        assert!(matches!(
+            risk.check(&request(OrdType::Market, 10_000.0, 0.0), None),
+            Err(RiskReject::NoReferencePrice(symbol)) if symbol == "USDJPY"
+        ));
+        assert!(
+            risk.check(&request(OrdType::Market, 10_000.0, 0.0), Some(150.0))
+                .is_ok()
+        );
+        assert!(matches!(
+            risk.check(&request(OrdType::Market, 10_000.0, 0.0), Some(150.1)),
+            Err(RiskReject::MaxNotional { .. })
+        ));
+        // a limit order is valued at its own price
+        assert!(
+            risk.check(&request(OrdType::Limit, 10_000.0, 150.0), None)
+                .is_ok()
+        );
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+
+use crate::cfg::{SelfTradeAction, SelfTradeCfg};
+use crate::order_manager::{Order, OrderManager, OrderState};
+use crate::server::fantasy::{OrdType, RequestMessage, Side};
+
+/// Finds the resting orders a new order would trade against among the other
+/// accounts of its beneficial owner.
+#[derive(Debug, Clone)]
+pub struct SelfTradePrevention {
+    action: SelfTradeAction,
+    /// Account to its beneficial owner.
+    owners: HashMap<String, String>,
+}
+
+impl SelfTradePrevention {
+    pub fn new(cfg: &SelfTradeCfg) -> Self {
+        let owners = cfg
+            .owners
+            .iter()
+            .flat_map(|(owner, accounts)| {
+                accounts
+                    .iter()
+                    .map(move |account| (account.clone(), owner.clone()))
+            })
+            .collect();
+        SelfTradePrevention {
+            action: cfg.action,
+            owners,
+        }
+    }
+
+    pub fn action(&self) -> SelfTradeAction {
+        self.action
+    }
+
+    pub fn owner(&self, account: &str) -> Option<&str> {
+        self.owners.get(account).map(String::as_str)
+    }
+
+    /// Live orders of other accounts of the same owner on the other side of
+    /// `req` it would cross: at or inside its limit price, any price for a
+    /// market order, and any resting market order (no price). While a replace
+    /// is pending either price may be working, the chain is given once by its
+    /// live order. Orders with a cancel pending are left out.
+    pub fn crossing<'a>(&self, req: &RequestMessage, om: &'a OrderManager) -> Vec<&'a Order> {
+        let Some(owner) = self.owner(&req.account) else {
+            return Vec::new();
+        };
+        let market = req.ord_type() == OrdType::Market;
+        let mut resting: Vec<_> = om
+            .open_orders()
+            .filter(|o| o.account != req.account && self.owner(&o.account) == Some(owner))
+            .filter(|o| o.symbol == req.symbol)
+            .filter(|o| match (req.side(), o.side) {
+                (Side::Buy, Side::Sell) => market || o.price <= req.price,
+                (Side::Sell, Side::Buy) => market || o.price <= 0.0 || o.price >= req.price,
+                _ => false,
+            })
+            .filter_map(|o| om.get("", &om.live_in_chain(&o.cl_ord_id)))
+            .filter(|o| o.state != OrderState::PendingCancel)
+            .collect();
+        resting.sort_by(|a, b| a.cl_ord_id.cmp(&b.cl_ord_id));
+        resting.dedup_by(|a, b| a.cl_ord_id == b.cl_ord_id);
+        resting
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::order_manager::tests::book;
+
+    fn prevention() -> SelfTradePrevention {
+        SelfTradePrevention::new(&SelfTradeCfg {
+            action: SelfTradeAction::RejectNew,
+            owners: HashMap::from([(
+                "bank".to_string(),
+                vec!["desk1".to_string(), "desk2".to_string()],
+            )]),
+        })
+    }
+
+    fn request(account: &str, side: Side, price: f64) -> RequestMessage {
+        let ord_type = if price > 0.0 {
+            OrdType::Limit
+        } else {
+            OrdType::Market
+        };
+        RequestMessage {
+            message: format!("{account}-{side:?}-{price}"),
+            client_id: "c1".to_string(),
+            account: account.to_string(),
+            symbol: "USDJPY".to_string(),
+            side: side as i32,
+            ord_type: ord_type as i32,
+            quantity: 1000.0,
+            price,
+            ..Default::default()
+        }
+    }
+
+    fn rest(om: &mut OrderManager, account: &str, side: Side, price: f64) -> String {
+        om.add_order(&mut request(account, side, price)).unwrap()
+    }
+
+    fn crossed(stp: &SelfTradePrevention, om: &OrderManager, req: &RequestMessage) -> Vec<String> {
+        stp.crossing(req, om)
+            .iter()
+            .map(|o| o.cl_ord_id.clone())
+            .collect()
+    }
+
+    #[test]
+    fn limit_order_crosses_at_or_inside_its_price() {
+        let stp = prevention();
+        let mut om = book();
+        let sell_150 = rest(&mut om, "desk2", Side::Sell, 150.0);
+        let sell_151 = rest(&mut om, "desk2", Side::Sell, 151.0);
+        let buy_149 = rest(&mut om, "desk2", Side::Buy, 149.0);
+        // same account, or another owner
+        rest(&mut om, "desk1", Side::Sell, 140.0);
+        rest(&mut om, "other", Side::Sell, 140.0);
+
+        assert!(crossed(&stp, &om, &request("desk1", Side::Buy, 149.9)).is_empty());
+        assert_eq!(
+            crossed(&stp, &om, &request("desk1", Side::Buy, 150.0)),
+            [sell_150.clone()]
+        );
+        let mut both = vec![sell_150, sell_151];
+        both.sort();
+        assert_eq!(
+            crossed(&stp, &om, &request("desk1", Side::Buy, 151.0)),
+            both
+        );
+        assert_eq!(
+            crossed(&stp, &om, &request("desk1", Side::Sell, 149.0)),
+            [buy_149]
+        );
+        assert!(crossed(&stp, &om, &request("desk1", Side::Sell, 149.1)).is_empty());
+        // no owner, no self-trade
+        assert!(crossed(&stp, &om, &request("other", Side::Buy, 200.0)).is_empty());
+    }
+
+    #[test]
+    fn market_orders_cross_at_any_price() {
+        let stp = prevention();
+        let mut om = book();
+        let sell = rest(&mut om, "desk2", Side::Sell, 155.0);
+        let market_buy = rest(&mut om, "desk2", Side::Buy, 0.0);
+
+        assert_eq!(
+            crossed(&stp, &om, &request("desk1", Side::Buy, 0.0)),
+            [sell]
+        );
+        // a resting market order has no price, any sell crosses it
+        assert_eq!(
+            crossed(&stp, &om, &request("desk1", Side::Sell, 999.0)),
+            [market_buy]
+        );
+    }
+}
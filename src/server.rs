@@ -1,13 +1,25 @@
-use log::{error, info};
+use log::{error, info, warn};
 use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::{Mutex, mpsc};
 use tokio::time::Duration;
-use tokio::time::sleep;
+use tokio::time::{Instant, sleep};
 
 use crate::ForwardRequest;
-use crate::cfg::GwConfig;
-use crate::order_manager::OrderManager;
-use crate::shared_data::SharedData;
+use crate::algo::{AlgoParams, ParentBook};
+use crate::cfg::{GwConfig, SelfTradeAction};
+use crate::disconnect::ClientMonitor;
+use crate::event_log::{Event, EventLog};
+use crate::exposure;
+use crate::fix_convert::gw_plugin::Plugin;
+use crate::idempotency::{Begin, IdempotencyCache, KeyOutcome};
+use crate::kill_switch::{self, Halt};
+use crate::order_manager::{OrderAnomaly, OrderManager, OrderState, log_anomaly};
+use crate::risk::{RiskCheck, RiskReject};
+use crate::self_trade::SelfTradePrevention;
+use crate::shared_data::Books;
+use crate::store::Record;
+use crate::symbol_lists::Listed;
 
 pub mod fantasy {
     tonic::include_proto!("fantasy"); // 这里的包名是 proto 文件中的 package 名
@@ -19,30 +31,584 @@ pub mod proto {
 }
 
 use fantasy::example_service_server::{ExampleService, ExampleServiceServer};
-use fantasy::{RequestMessage, ResponseMessage};
+use fantasy::{
+    CancelOutcome, CancelResult, ExposureList, ExposureQuery, KillSwitchRequest, KillSwitchStatus,
+    MarkPrice, MassCancelReport, MassCancelRequest, OrderChain, OrderReport, ParentOrderReport,
+    ParentOrderRequest, PositionList, PositionQuery, PositionReport, RequestMessage,
+    ResponseMessage, ResumeRequest, SymbolListQuery, SymbolListReport, SymbolListUpdate,
+    ThrottleQuery, ThrottleStatus,
+};
 use futures_util::Stream; // 使用 futures_util 提供的 Stream trait
 use std::pin::Pin;
 use tokio_stream::wrappers::ReceiverStream; // 引入 tokio_stream
-use tonic::{Request, Response, Status, transport::Server};
+use tonic::{Code, Request, Response, Status, transport::Server};
 
 pub struct MyExampleService {
     order_sender: mpsc::UnboundedSender<ForwardRequest>,
-    shared_data: Arc<Mutex<SharedData>>,
+    events: Arc<Mutex<EventLog>>,
     gw_config: GwConfig,
-    order_manager: Arc<Mutex<OrderManager>>,
+    books: Books,
+    plugin: Arc<dyn Plugin>,
+    idempotency: std::sync::Mutex<IdempotencyCache>,
+    clients: Arc<Mutex<ClientMonitor>>,
+    risk: RiskCheck,
+    self_trade: SelfTradePrevention,
+}
+
+fn anomaly_status(e: OrderAnomaly) -> Status {
+    match e {
+        OrderAnomaly::DuplicateClientRef(_) => Status::already_exists(e.to_string()),
+        OrderAnomaly::Persistence(_) => Status::unavailable(e.to_string()),
+        OrderAnomaly::OtherClient { .. } => Status::permission_denied(e.to_string()),
+        OrderAnomaly::Mismatch { .. } => Status::invalid_argument(e.to_string()),
+        _ => Status::failed_precondition(e.to_string()),
+    }
+}
+
+fn risk_status(request: &RequestMessage, reason: RiskReject) -> Status {
+    warn!(
+        "risk reject {} {}: {}",
+        request.symbol, request.message, reason
+    );
+    Status::failed_precondition(format!("risk: {reason}"))
+}
+
+/// Resting orders an order would cross and the self-trade action for them.
+struct SelfTrade {
+    action: SelfTradeAction,
+    message: String,
+    /// Cancel requests of the resting orders.
+    resting: Vec<RequestMessage>,
+}
+
+/// How often clients are checked for cancel-on-disconnect.
+const DISCONNECT_CHECK: Duration = Duration::from_millis(200);
+
+/// How often parent orders are worked.
+const ALGO_TICK: Duration = Duration::from_millis(100);
+
+/// Events read from the event log at a time for a `ServerStream` subscriber.
+const STREAM_BATCH: usize = 1000;
+
+/// How often the order book is checked for the outcome of mass cancel requests.
+const MASS_CANCEL_POLL: Duration = Duration::from_millis(50);
+
+/// Outcome of a mass cancel for the order a cancel request is for.
+fn cancel_outcome(om: &OrderManager, req: &RequestMessage) -> CancelOutcome {
+    CancelOutcome {
+        cl_ord_id: req.orig_cl_ord_id.clone(),
+        client_ref: om
+            .get("", &req.orig_cl_ord_id)
+            .and_then(|o| o.client_ref.clone())
+            .unwrap_or_default(),
+        ..Default::default()
+    }
+}
+
+/// Cancels the parent orders matching `filter` so they send no more child
+/// orders, then books and sends a cancel for every open order matching it,
+/// their working children included. The outcomes are pending until the broker
+/// answers.
+async fn send_cancels(
+    books: &Books,
+    order_sender: &mpsc::UnboundedSender<ForwardRequest>,
+    filter: &MassCancelRequest,
+) -> Vec<CancelOutcome> {
+    let mut parents = books.parents.lock().await;
+    let mut om = books.orders.lock().await;
+    // child cancels are booked by the parent cancel
+    let mut booked = parents.mass_cancel(&mut om, filter);
+    let mut outcomes = Vec::new();
+    for mut req in om.mass_cancel_requests(filter) {
+        match om.add_cancel(&mut req) {
+            Ok(_) => booked.push(req),
+            Err(e) => outcomes.push(CancelOutcome {
+                result: CancelResult::NotSent as i32,
+                text: e.to_string(),
+                ..cancel_outcome(&om, &req)
+            }),
+        }
+    }
+    for req in booked {
+        outcomes.push(CancelOutcome {
+            cancel_cl_ord_id: req.message.clone(),
+            ..cancel_outcome(&om, &req)
+        });
+        if order_sender
+            .send(ForwardRequest::CancelRequest(req))
+            .is_err()
+        {
+            info!("send mass cancel error");
+        }
+    }
+    outcomes.sort_by(|a, b| a.cl_ord_id.cmp(&b.cl_ord_id));
+    outcomes
+}
+
+/// Sends a `ServerStream` subscriber the events of the log from `next_seq`
+/// on, in batches so the log is not held while it takes them. False once the
+/// subscriber is gone.
+async fn catch_up(
+    events: &Mutex<EventLog>,
+    tx: &mpsc::Sender<Result<ResponseMessage, Status>>,
+    next_seq: &mut u64,
+) -> bool {
+    loop {
+        let batch = events.lock().await.get_from(*next_seq, STREAM_BATCH);
+        for event in &batch {
+            if !push_event(tx, event).await {
+                return false;
+            }
+        }
+        let Some(last) = batch.last() else {
+            return true;
+        };
+        *next_seq = last.seq + 1;
+        if batch.len() < STREAM_BATCH {
+            return true;
+        }
+    }
+}
+
+async fn push_event(tx: &mpsc::Sender<Result<ResponseMessage, Status>>, event: &Event) -> bool {
+    let message = ResponseMessage {
+        message: format!("Server Stream push: {:?}", event),
+    };
+    match tx.send(Ok(message)).await {
+        Ok(()) => true,
+        Err(e) => {
+            error!("Failed to send message: {}", e);
+            false
+        }
+    }
+}
+
+/// Updates the outcomes still pending from the order book, returns whether
+/// any is left.
+fn update_cancel_outcomes(om: &OrderManager, outcomes: &mut [CancelOutcome]) -> bool {
+    let mut pending = false;
+    for outcome in outcomes
+        .iter_mut()
+        .filter(|o| o.result() == CancelResult::Unspecified)
+    {
+        let Some(order) = om.get("", &outcome.cl_ord_id) else {
+            continue;
+        };
+        outcome.state = format!("{:?}", order.state);
+        outcome.result = match order.state {
+            OrderState::PendingCancel => {
+                pending = true;
+                continue;
+            }
+            OrderState::Canceled => CancelResult::Canceled as i32,
+            _ => CancelResult::Rejected as i32,
+        };
+    }
+    pending
+}
+
+fn mass_cancel_report(mut outcomes: Vec<CancelOutcome>) -> MassCancelReport {
+    let mut report = MassCancelReport {
+        matched: outcomes.len() as u32,
+        ..Default::default()
+    };
+    for outcome in &mut outcomes {
+        if outcome.result() == CancelResult::Unspecified {
+            outcome.result = CancelResult::TimedOut as i32;
+        }
+        match outcome.result() {
+            CancelResult::Canceled => report.canceled += 1,
+            CancelResult::Rejected => report.rejected += 1,
+            CancelResult::NotSent => report.not_sent += 1,
+            _ => report.timed_out += 1,
+        }
+    }
+    report.outcomes = outcomes;
+    report
 }
 
 impl MyExampleService {
     pub fn new(
         sender: mpsc::UnboundedSender<ForwardRequest>,
-        sd: Arc<tokio::sync::Mutex<SharedData>>,
+        events: Arc<tokio::sync::Mutex<EventLog>>,
         gw_cfg: GwConfig,
+        books: Books,
+        plugin: Arc<dyn Plugin>,
     ) -> MyExampleService {
+        let retention = Duration::from_secs(gw_cfg.idempotency_retention_secs);
+        let clients = ClientMonitor::new(&gw_cfg.cancel_on_disconnect);
+        let risk = RiskCheck::new(&gw_cfg.risk);
+        let self_trade = SelfTradePrevention::new(&gw_cfg.self_trade);
+        let idempotency = IdempotencyCache::new(retention, books.journal.clone());
         MyExampleService {
             order_sender: sender,
-            shared_data: sd,
+            events,
             gw_config: gw_cfg,
-            order_manager: Arc::new(Mutex::new(OrderManager::new())),
+            books,
+            plugin,
+            idempotency: std::sync::Mutex::new(idempotency),
+            clients: Arc::new(Mutex::new(clients)),
+            risk,
+            self_trade,
+        }
+    }
+
+    /// Answers the retries of requests handled before a restart.
+    pub fn restore_idempotency(&self, outcomes: Vec<KeyOutcome>) {
+        self.idempotency.lock().unwrap().restore(outcomes);
+    }
+
+    /// Cancels the open orders of the clients with cancel-on-disconnect that
+    /// went silent, and pushes why to the execution event stream.
+    pub fn watch_disconnects(&self) {
+        let clients = Arc::clone(&self.clients);
+        let books = self.books.clone();
+        let order_sender = self.order_sender.clone();
+        let events = Arc::clone(&self.events);
+        tokio::spawn(async move {
+            loop {
+                sleep(DISCONNECT_CHECK).await;
+                let gone = clients.lock().await.disconnected();
+                for (client_id, silent) in gone {
+                    let filter = MassCancelRequest {
+                        client_id: client_id.clone(),
+                        ..Default::default()
+                    };
+                    let outcomes = send_cancels(&books, &order_sender, &filter).await;
+                    let message = format!(
+                        "cancel on disconnect: client {} silent for {} ms, {} open orders canceled",
+                        client_id,
+                        silent.as_millis(),
+                        outcomes.len()
+                    );
+                    warn!("{}", message);
+                    books.record(&Record::event(message.clone()));
+                    events.lock().await.add_message(message);
+                }
+            }
+        });
+    }
+
+    /// Sends the child orders of the working parent orders as they come due,
+    /// each after the risk and self-trade checks of `check_child`. A child
+    /// failing them is rejected, which stops its parent order.
+    pub fn work_parent_orders(self: &Arc<Self>) {
+        let service = Arc::clone(self);
+        tokio::spawn(async move {
+            let books = &service.books;
+            loop {
+                sleep(ALGO_TICK).await;
+                let children = {
+                    let kill_switch = books.kill_switch.lock().await;
+                    let symbol_lists = books.symbol_lists.lock().await;
+                    let mut parents = books.parents.lock().await;
+                    let mut om = books.orders.lock().await;
+                    parents.step(&mut om, chrono::Utc::now().timestamp_millis(), |parent| {
+                        kill_switch.halted(&parent.account).is_some()
+                            || matches!(
+                                symbol_lists.check(&parent.account, &parent.symbol),
+                                Some(Listed::Restricted(_))
+                            )
+                    })
+                };
+                for child in children {
+                    if let Err(status) = service.check_child(&child).await {
+                        let message = format!(
+                            "child order {} rejected: {}",
+                            child.message,
+                            status.message()
+                        );
+                        log_anomaly(books.orders.lock().await.reject(&child.message));
+                        service.audit(message).await;
+                        continue;
+                    }
+                    if service
+                        .order_sender
+                        .send(ForwardRequest::RequestMessage(child))
+                        .is_err()
+                    {
+                        info!("send child order error");
+                    }
+                }
+            }
+        });
+    }
+
+    /// Books an order entry request and forwards it to the FIX session.
+    ///
+    /// A request carrying an idempotency key already seen gets the outcome of
+    /// the first one back and nothing is booked or sent again, a retry while
+    /// the first one is still worked is refused. With `pre_trade` the request
+    /// has to pass the risk checks first.
+    async fn submit(
+        &self,
+        method: &'static str,
+        mut request: RequestMessage,
+        pre_trade: bool,
+        add: fn(&mut OrderManager, &mut RequestMessage) -> Result<String, OrderAnomaly>,
+        forward: fn(RequestMessage) -> ForwardRequest,
+    ) -> Result<Response<ResponseMessage>, Status> {
+        self.plugin
+            .check_extra_tags(&request)
+            .map_err(Status::invalid_argument)?;
+        let client_id = request.client_id.clone();
+        self.clients.lock().await.touch(&client_id);
+        let key = request.idempotency_key.clone();
+        let in_flight = match IdempotencyCache::begin(&self.idempotency, &client_id, &key, method) {
+            Begin::Start(in_flight) => in_flight,
+            Begin::Answer(outcome) => {
+                info!("{} with idempotency key {} already handled", method, key);
+                return outcome.map(|message| Response::new(ResponseMessage { message }));
+            }
+        };
+
+        // a cancel or replace is checked as the order it changes
+        let resolved = self
+            .books
+            .orders
+            .lock()
+            .await
+            .resolve_request(&mut request)
+            .map_err(anomaly_status);
+        let risk = match (resolved, pre_trade) {
+            (Err(status), _) => Err(status),
+            (Ok(()), true) => self.pre_trade(&request).await,
+            (Ok(()), false) => Ok(()),
+        };
+        if let Err(status) = risk {
+            in_flight.complete(&Err(status.clone()));
+            return Err(status);
+        }
+        let (outcome, self_trade, cancels) = {
+            let parents = self.books.parents.lock().await;
+            let mut om = self.books.orders.lock().await;
+            let self_trade = match pre_trade {
+                true => self.crossing(&request, &om),
+                false => None,
+            };
+            let checked = match &self_trade {
+                Some(st) if st.action == SelfTradeAction::RejectNew => {
+                    Err(Status::failed_precondition(st.message.clone()))
+                }
+                _ if pre_trade => self.check_credit(&request, &parents, &om).await,
+                _ => Ok(()),
+            };
+            let outcome = match checked {
+                Ok(()) => add(&mut om, &mut request).map_err(anomaly_status),
+                Err(status) => Err(status),
+            };
+            // resting orders are only canceled for an order that goes out
+            match (self_trade, &outcome) {
+                (Some(st), Ok(_)) => (
+                    outcome,
+                    Some(st.message.clone()),
+                    self.cancel_resting(st, &mut om),
+                ),
+                (Some(st), Err(_)) if st.action == SelfTradeAction::RejectNew => {
+                    (outcome, Some(st.message), Vec::new())
+                }
+                _ => (outcome, None, Vec::new()),
+            }
+        };
+        if let Some(message) = self_trade {
+            warn!("{}", message);
+            self.audit(message).await;
+        }
+        match &outcome {
+            Ok(_) => {
+                self.send_resting_cancels(cancels);
+                if self.order_sender.send(forward(request)).is_err() {
+                    info!("send {} error", method);
+                }
+            }
+            // nothing was booked, a retry may succeed
+            Err(status) if status.code() == Code::Unavailable => return Err(status.clone()),
+            Err(_) => {}
+        }
+        in_flight.complete(&outcome);
+        outcome.map(|message| Response::new(ResponseMessage { message }))
+    }
+
+    /// Operator of the admin token in the `authorization` metadata.
+    async fn authorize<T>(&self, request: &Request<T>) -> Result<String, Status> {
+        let token = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| Status::unauthenticated("admin token required"))?;
+        self.gw_config
+            .admin_tokens
+            .iter()
+            .find(|(_, t)| !t.is_empty() && t.as_str() == token)
+            .map(|(operator, _)| operator.clone())
+            .ok_or_else(|| Status::permission_denied("unknown admin token"))
+    }
+
+    /// Journals an event of the audit trail and pushes it to the execution
+    /// event stream.
+    async fn audit(&self, message: String) {
+        self.books.record(&Record::event(message.clone()));
+        self.events.lock().await.add_message(message);
+    }
+
+    /// Resting orders of other accounts of its beneficial owner an order
+    /// would cross, `None` when there are none.
+    fn crossing(&self, request: &RequestMessage, om: &OrderManager) -> Option<SelfTrade> {
+        let resting: Vec<_> = self.self_trade.crossing(request, om);
+        if resting.is_empty() {
+            return None;
+        }
+        let crossed = resting
+            .iter()
+            .map(|o| format!("{} of account {}", o.cl_ord_id, o.account))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let action = self.self_trade.action();
+        let message = format!(
+            "self-trade: order {} of account {} in {} would cross {} (owner {}), {:?}",
+            request.message,
+            request.account,
+            request.symbol,
+            crossed,
+            self.self_trade.owner(&request.account).unwrap_or_default(),
+            action
+        );
+        Some(SelfTrade {
+            action,
+            message,
+            resting: resting.iter().map(|o| o.cancel_request()).collect(),
+        })
+    }
+
+    /// Books, with `cancel_resting`, the cancels of the resting orders an
+    /// order crosses once the order itself is booked. They are returned to be
+    /// sent ahead of it, on the same FIX session.
+    fn cancel_resting(&self, self_trade: SelfTrade, om: &mut OrderManager) -> Vec<RequestMessage> {
+        if self_trade.action != SelfTradeAction::CancelResting {
+            return Vec::new();
+        }
+        let mut cancels = Vec::new();
+        for mut cancel in self_trade.resting {
+            if log_anomaly(om.add_cancel(&mut cancel)).is_some() {
+                cancels.push(cancel);
+            }
+        }
+        cancels
+    }
+
+    fn send_resting_cancels(&self, cancels: Vec<RequestMessage>) {
+        for cancel in cancels {
+            if self
+                .order_sender
+                .send(ForwardRequest::CancelRequest(cancel))
+                .is_err()
+            {
+                info!("send self-trade cancel error");
+            }
+        }
+    }
+
+    /// Kill switch, symbol list and risk checks of an order before it is
+    /// booked, the risk checks against the mark price of its symbol. An order
+    /// on a watch list passing them is flagged in the audit trail. The credit
+    /// limit and self-trades are checked as the order is booked, see
+    /// `check_credit` and `crossing`.
+    async fn pre_trade(&self, request: &RequestMessage) -> Result<(), Status> {
+        if let Some(halt) = self.books.kill_switch.lock().await.halted(&request.account) {
+            warn!("halted, order {} refused", request.message);
+            return Err(Status::failed_precondition(format!(
+                "trading halted by {}: {}",
+                halt.operator, halt.reason
+            )));
+        }
+        let listed = self
+            .books
+            .symbol_lists
+            .lock()
+            .await
+            .check(&request.account, &request.symbol);
+        if let Some(Listed::Restricted(group)) = &listed {
+            let message = format!(
+                "restricted: order {} of account {} in {} blocked (group {})",
+                request.message, request.account, request.symbol, group
+            );
+            warn!("{}", message);
+            self.audit(message.clone()).await;
+            return Err(Status::failed_precondition(message));
+        }
+        let reference = self
+            .books
+            .positions
+            .lock()
+            .await
+            .mark_price(&request.symbol);
+        self.risk
+            .check(request, reference)
+            .map_err(|reason| risk_status(request, reason))?;
+        if let Some(Listed::Watch(group)) = listed {
+            let message = format!(
+                "watch list: order {} of account {} in {} (group {})",
+                request.message, request.account, request.symbol, group
+            );
+            info!("{}", message);
+            self.audit(message).await;
+        }
+        Ok(())
+    }
+
+    /// Credit limit check of an order against the exposure of its account.
+    /// Made under the `parents` and `orders` locks the order is then booked
+    /// under, so two orders cannot both pass on the same headroom.
+    async fn check_credit(
+        &self,
+        request: &RequestMessage,
+        parents: &ParentBook,
+        om: &OrderManager,
+    ) -> Result<(), Status> {
+        if self.risk.credit_limit(&request.account).is_none() {
+            return Ok(());
+        }
+        let positions = self.books.positions.lock().await;
+        let exposure = exposure::exposures(parents, om, &positions)
+            .get(&request.account)
+            .map(|e| e.total())
+            .unwrap_or_default();
+        let added = exposure::added_by(request, om, &positions);
+        self.risk
+            .check_credit(request, exposure, added)
+            .map_err(|reason| risk_status(request, reason))
+    }
+
+    /// Checks of a child order the algo booked, those of `pre_trade` it has
+    /// not passed with its parent order: the risk limits, the collar against
+    /// the mark price as it is now, and self-trade prevention. Halts and
+    /// restricted symbols stop the parent before its children are booked, and
+    /// the parent quantity was checked against the credit limit.
+    async fn check_child(&self, child: &RequestMessage) -> Result<(), Status> {
+        let reference = self.books.positions.lock().await.mark_price(&child.symbol);
+        self.risk
+            .check(child, reference)
+            .map_err(|reason| risk_status(child, reason))?;
+        let (self_trade, cancels) = {
+            let mut om = self.books.orders.lock().await;
+            match self.crossing(child, &om) {
+                Some(self_trade) => (
+                    Some((self_trade.action, self_trade.message.clone())),
+                    self.cancel_resting(self_trade, &mut om),
+                ),
+                None => (None, Vec::new()),
+            }
+        };
+        self.send_resting_cancels(cancels);
+        let Some((action, message)) = self_trade else {
+            return Ok(());
+        };
+        warn!("{}", message);
+        self.audit(message.clone()).await;
+        match action {
+            SelfTradeAction::RejectNew => Err(Status::failed_precondition(message)),
+            SelfTradeAction::CancelResting | SelfTradeAction::Allow => Ok(()),
         }
     }
 }
@@ -54,28 +620,424 @@ impl ExampleService for MyExampleService {
         &self,
         request: Request<RequestMessage>,
     ) -> Result<Response<ResponseMessage>, Status> {
-        let request = request.into_inner();
-        /*
-            let mut om = self.order_manager.lock().await;
-            let result = om.check_and_insert(&request.message).await;
-            if result {
-                return Ok(Response::new(ResponseMessage {
-                    message: "Duplicate order number".to_string(),
-                }));
-            }
-        */
         // let message = request.into_inner().message;
-        if let Err(_) = self
-            .order_sender
-            .send(ForwardRequest::RequestMessage(request))
-        {
-            info!("send order error");
+        self.submit(
+            "order",
+            request.into_inner(),
+            true,
+            OrderManager::add_order,
+            ForwardRequest::RequestMessage,
+        )
+        .await
+    }
+
+    async fn cancel_order(
+        &self,
+        request: Request<RequestMessage>,
+    ) -> Result<Response<ResponseMessage>, Status> {
+        self.submit(
+            "cancel",
+            request.into_inner(),
+            false,
+            OrderManager::add_cancel,
+            ForwardRequest::CancelRequest,
+        )
+        .await
+    }
+
+    async fn replace_order(
+        &self,
+        request: Request<RequestMessage>,
+    ) -> Result<Response<ResponseMessage>, Status> {
+        self.submit(
+            "replace",
+            request.into_inner(),
+            true,
+            OrderManager::add_replace,
+            ForwardRequest::ReplaceRequest,
+        )
+        .await
+    }
+
+    async fn get_order(
+        &self,
+        request: Request<RequestMessage>,
+    ) -> Result<Response<OrderReport>, Status> {
+        let request = request.into_inner();
+        let om = self.books.orders.lock().await;
+        match om.get(&request.client_id, &request.message) {
+            Some(order) => Ok(Response::new(order.to_report())),
+            None => Err(Status::not_found(format!(
+                "unknown order {}",
+                request.message
+            ))),
+        }
+    }
+
+    async fn get_order_chain(
+        &self,
+        request: Request<RequestMessage>,
+    ) -> Result<Response<OrderChain>, Status> {
+        let request = request.into_inner();
+        let om = self.books.orders.lock().await;
+        match om.get_chain(&request.client_id, &request.message) {
+            Some((orders, live_cl_ord_id)) => Ok(Response::new(OrderChain {
+                chain_id: orders[0].chain_id.clone(),
+                live_cl_ord_id,
+                orders: orders.into_iter().map(|o| o.to_report()).collect(),
+            })),
+            None => Err(Status::not_found(format!(
+                "unknown order {}",
+                request.message
+            ))),
+        }
+    }
+
+    async fn get_positions(
+        &self,
+        request: Request<PositionQuery>,
+    ) -> Result<Response<PositionList>, Status> {
+        let query = request.into_inner();
+        let positions = self.books.positions.lock().await;
+        Ok(Response::new(PositionList {
+            positions: positions.query(&query.account, &query.symbol),
+        }))
+    }
+
+    type SubscribePositionsStream =
+        Pin<Box<dyn Stream<Item = Result<PositionReport, Status>> + Send>>;
+
+    async fn subscribe_positions(
+        &self,
+        request: Request<PositionQuery>,
+    ) -> Result<Response<Self::SubscribePositionsStream>, Status> {
+        let query = request.into_inner();
+        let (tx, rx) = mpsc::channel(4000);
+        let positions = Arc::clone(&self.books.positions);
+        let interval = self.gw_config.interval;
+
+        tokio::spawn(async move {
+            let mut seq = 0;
+            loop {
+                let updates = {
+                    let positions = positions.lock().await;
+                    let updates = positions.updated_since(&query.account, &query.symbol, seq);
+                    seq = positions.seq();
+                    updates
+                };
+                for update in updates {
+                    if tx.send(Ok(update)).await.is_err() {
+                        info!("position subscriber gone");
+                        return;
+                    }
+                }
+                sleep(Duration::from_millis(interval)).await;
+            }
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+
+    async fn set_mark_price(
+        &self,
+        request: Request<MarkPrice>,
+    ) -> Result<Response<ResponseMessage>, Status> {
+        let operator = self.authorize(&request).await?;
+        let mark = request.into_inner();
+        if mark.symbol.is_empty() || !mark.price.is_finite() {
+            return Err(Status::invalid_argument("symbol and price are required"));
         }
+        info!("mark {} {} set by {}", mark.symbol, mark.price, operator);
+        self.books
+            .positions
+            .lock()
+            .await
+            .set_mark(&mark.symbol, mark.price);
         Ok(Response::new(ResponseMessage {
-            message: format!("Hello from Unary: {}", "hello"),
+            message: format!("mark {} {}", mark.symbol, mark.price),
         }))
     }
 
+    async fn heartbeat(
+        &self,
+        request: Request<RequestMessage>,
+    ) -> Result<Response<ResponseMessage>, Status> {
+        let client_id = request.into_inner().client_id;
+        if client_id.is_empty() {
+            return Err(Status::invalid_argument("client_id is required"));
+        }
+        self.clients.lock().await.touch(&client_id);
+        Ok(Response::new(ResponseMessage { message: client_id }))
+    }
+
+    async fn submit_parent_order(
+        &self,
+        request: Request<ParentOrderRequest>,
+    ) -> Result<Response<ResponseMessage>, Status> {
+        let request = request.into_inner();
+        let params = AlgoParams::from_request(&request).map_err(Status::invalid_argument)?;
+        let Some(order) = request
+            .order
+            .as_ref()
+            .filter(|o| o.quantity > 0.0 && !o.symbol.is_empty())
+        else {
+            return Err(Status::invalid_argument(
+                "order with symbol and quantity is required",
+            ));
+        };
+        self.plugin
+            .check_extra_tags(order)
+            .map_err(Status::invalid_argument)?;
+        self.clients.lock().await.touch(&order.client_id);
+        let key = &order.idempotency_key;
+        let in_flight =
+            match IdempotencyCache::begin(&self.idempotency, &order.client_id, key, "parent") {
+                Begin::Start(in_flight) => in_flight,
+                Begin::Answer(outcome) => {
+                    info!("parent order with idempotency key {} already handled", key);
+                    return outcome.map(|message| Response::new(ResponseMessage { message }));
+                }
+            };
+
+        if let Err(status) = self.pre_trade(order).await {
+            in_flight.complete(&Err(status.clone()));
+            return Err(status);
+        }
+        let outcome = {
+            let mut parents = self.books.parents.lock().await;
+            let mut om = self.books.orders.lock().await;
+            match self.check_credit(order, &parents, &om).await {
+                Ok(()) => parents
+                    .add(
+                        &mut om,
+                        &request,
+                        params,
+                        chrono::Utc::now().timestamp_millis(),
+                    )
+                    .map_err(anomaly_status),
+                Err(status) => Err(status),
+            }
+        };
+        match &outcome {
+            // nothing was booked, a retry may succeed
+            Err(status) if status.code() == Code::Unavailable => return Err(status.clone()),
+            _ => in_flight.complete(&outcome),
+        }
+        outcome.map(|message| Response::new(ResponseMessage { message }))
+    }
+
+    async fn get_parent_order(
+        &self,
+        request: Request<RequestMessage>,
+    ) -> Result<Response<ParentOrderReport>, Status> {
+        let request = request.into_inner();
+        let parents = self.books.parents.lock().await;
+        let om = self.books.orders.lock().await;
+        match parents.get(&request.client_id, &request.message) {
+            Some(parent) => Ok(Response::new(parents.report(parent, &om))),
+            None => Err(Status::not_found(format!(
+                "unknown parent order {}",
+                request.message
+            ))),
+        }
+    }
+
+    async fn cancel_parent_order(
+        &self,
+        request: Request<RequestMessage>,
+    ) -> Result<Response<ResponseMessage>, Status> {
+        let request = request.into_inner();
+        let mut parents = self.books.parents.lock().await;
+        let mut om = self.books.orders.lock().await;
+        let (parent_id, cancels) = parents
+            .cancel(&mut om, &request.client_id, &request.message)
+            .map_err(|e| Status::not_found(e.to_string()))?;
+        info!(
+            "parent order {} canceled, {} child cancels",
+            parent_id,
+            cancels.len()
+        );
+        for cancel in cancels {
+            if self
+                .order_sender
+                .send(ForwardRequest::CancelRequest(cancel))
+                .is_err()
+            {
+                info!("send child cancel error");
+            }
+        }
+        Ok(Response::new(ResponseMessage { message: parent_id }))
+    }
+
+    async fn get_throttle_status(
+        &self,
+        _request: Request<ThrottleQuery>,
+    ) -> Result<Response<ThrottleStatus>, Status> {
+        let status = self
+            .books
+            .throttle
+            .lock()
+            .await
+            .status(std::time::Instant::now());
+        Ok(Response::new(status))
+    }
+
+    async fn get_exposure(
+        &self,
+        request: Request<ExposureQuery>,
+    ) -> Result<Response<ExposureList>, Status> {
+        let account = request.into_inner().account;
+        let parents = self.books.parents.lock().await;
+        let om = self.books.orders.lock().await;
+        let positions = self.books.positions.lock().await;
+        let mut exposures = exposure::exposures(&parents, &om, &positions);
+        if !account.is_empty() {
+            exposures.retain(|a, _| *a == account);
+            exposures.entry(account).or_default();
+        }
+        let accounts = exposures
+            .iter()
+            .map(|(account, e)| e.report(account, self.risk.credit_limit(account)))
+            .collect();
+        Ok(Response::new(ExposureList { accounts }))
+    }
+
+    async fn get_symbol_lists(
+        &self,
+        _request: Request<SymbolListQuery>,
+    ) -> Result<Response<SymbolListReport>, Status> {
+        let groups = self.books.symbol_lists.lock().await.reports();
+        Ok(Response::new(SymbolListReport { groups }))
+    }
+
+    async fn update_symbol_list(
+        &self,
+        request: Request<SymbolListUpdate>,
+    ) -> Result<Response<SymbolListReport>, Status> {
+        let operator = self.authorize(&request).await?;
+        let update = request.into_inner();
+        if update.group.is_empty() {
+            return Err(Status::invalid_argument("group is required"));
+        }
+        let mut symbol_lists = self.books.symbol_lists.lock().await;
+        symbol_lists
+            .update(
+                &update.group,
+                &update.symbol,
+                update.list(),
+                &update.accounts,
+            )
+            .map_err(|e| Status::internal(format!("save symbol lists: {e}")))?;
+        let message = format!(
+            "symbol lists: group {} symbol {} {:?} accounts {:?} by {}",
+            update.group,
+            update.symbol,
+            update.list(),
+            update.accounts,
+            operator
+        );
+        info!("{}", message);
+        self.audit(message).await;
+        Ok(Response::new(SymbolListReport {
+            groups: symbol_lists.reports(),
+        }))
+    }
+
+    async fn kill_switch(
+        &self,
+        request: Request<KillSwitchRequest>,
+    ) -> Result<Response<KillSwitchStatus>, Status> {
+        let operator = self.authorize(&request).await?;
+        let request = request.into_inner();
+        let mut kill_switch = self.books.kill_switch.lock().await;
+        kill_switch.halt(
+            &request.account,
+            Halt {
+                operator: operator.clone(),
+                reason: request.reason.clone(),
+                since_ms: chrono::Utc::now().timestamp_millis(),
+            },
+        );
+        let mut cancels_sent = 0;
+        if request.cancel_open_orders {
+            let filter = MassCancelRequest {
+                account: request.account.clone(),
+                ..Default::default()
+            };
+            let outcomes = send_cancels(&self.books, &self.order_sender, &filter).await;
+            cancels_sent = outcomes
+                .iter()
+                .filter(|o| o.result != CancelResult::NotSent as i32)
+                .count() as u32;
+        }
+        let message = format!(
+            "kill switch: {} halted by {}: {}, {} cancels sent",
+            kill_switch::scope(&request.account),
+            operator,
+            request.reason,
+            cancels_sent
+        );
+        self.audit(message).await;
+        Ok(Response::new(KillSwitchStatus {
+            halts: kill_switch.reports(),
+            cancels_sent,
+        }))
+    }
+
+    async fn resume(
+        &self,
+        request: Request<ResumeRequest>,
+    ) -> Result<Response<KillSwitchStatus>, Status> {
+        let operator = self.authorize(&request).await?;
+        let account = request.into_inner().account;
+        let mut kill_switch = self.books.kill_switch.lock().await;
+        if !kill_switch.resume(&account, &operator) {
+            return Err(Status::not_found(format!(
+                "{} is not halted",
+                kill_switch::scope(&account)
+            )));
+        }
+        let message = format!(
+            "kill switch: {} resumed by {}",
+            kill_switch::scope(&account),
+            operator
+        );
+        self.audit(message).await;
+        Ok(Response::new(KillSwitchStatus {
+            halts: kill_switch.reports(),
+            cancels_sent: 0,
+        }))
+    }
+
+    async fn mass_cancel(
+        &self,
+        request: Request<MassCancelRequest>,
+    ) -> Result<Response<MassCancelReport>, Status> {
+        let filter = request.into_inner();
+        let timeout = match filter.timeout_ms {
+            0 => self.gw_config.mass_cancel_timeout_ms,
+            timeout => timeout,
+        };
+        let deadline = Instant::now() + Duration::from_millis(timeout);
+
+        let mut outcomes = send_cancels(&self.books, &self.order_sender, &filter).await;
+        info!("mass cancel {:?}: {} orders", filter, outcomes.len());
+
+        loop {
+            let pending = update_cancel_outcomes(&*self.books.orders.lock().await, &mut outcomes);
+            if !pending || Instant::now() >= deadline {
+                break;
+            }
+            sleep(MASS_CANCEL_POLL).await;
+        }
+        let report = mass_cancel_report(outcomes);
+        info!(
+            "mass cancel done: {} canceled, {} rejected, {} timed out, {} not sent",
+            report.canceled, report.rejected, report.timed_out, report.not_sent
+        );
+        Ok(Response::new(report))
+    }
+
     // 2. 服务端流式 RPC 调用
     type ServerStreamStream = Pin<Box<dyn Stream<Item = Result<ResponseMessage, Status>> + Send>>;
 
@@ -86,30 +1048,39 @@ impl ExampleService for MyExampleService {
         info!("服务端流式调用");
         // let message = request.into_inner().message;
         let (tx, rx) = mpsc::channel(4000);
-        let shared_data_clone = Arc::clone(&self.shared_data);
-        let interval = self.gw_config.interval;
+        let events = Arc::clone(&self.events);
+        let mut live = events.lock().await.subscribe();
 
         tokio::spawn(async move {
-            let mut count: u64 = 1;
+            // the events logged so far, then each one as it is added; the
+            // ones logged after subscribing come both ways and are sent once
+            let mut next_seq: u64 = 1;
+            if !catch_up(&events, &tx, &mut next_seq).await {
+                return;
+            }
             loop {
-                sleep(Duration::from_millis(interval)).await;
-                {
-                    let data = shared_data_clone.lock().await;
-                    let vec = data.get_messages_from(count);
-                    for info in &vec {
-                        if let Err(e) = tx
-                            .send(Ok(ResponseMessage {
-                                message: format!("Server Stream push: {:?}", info),
-                            }))
-                            .await
-                        {
-                            error!("Failed to send message: {}", e);
+                let received = tokio::select! {
+                    _ = tx.closed() => return,
+                    received = live.recv() => received,
+                };
+                match received {
+                    Ok(event) if event.seq < next_seq => {}
+                    Ok(event) => {
+                        if !push_event(&tx, &event).await {
                             return;
                         }
+                        next_seq = event.seq + 1;
                     }
-                    if let Some(last) = vec.last() {
-                        count = last.counter + 1;
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!(
+                            "stream subscriber missed {} live events, catching up from the log",
+                            missed
+                        );
+                        if !catch_up(&events, &tx, &mut next_seq).await {
+                            return;
+                        }
                     }
+                    Err(RecvError::Closed) => return,
                 }
             }
         });
@@ -145,19 +1116,29 @@ impl ExampleService for MyExampleService {
         info!("双向流式调用");
         let mut stream = request.into_inner();
         let (tx, rx) = mpsc::channel(4);
+        let clients = Arc::clone(&self.clients);
 
         tokio::spawn(async move {
-            while let Some(req) = match stream.message().await {
-                Ok(Some(req)) => Some(req),
-                Ok(None) => {
-                    error!("客户端流已关闭");
-                    return;
-                }
-                Err(e) => {
-                    error!("接收消息时出错: {}", e);
-                    return;
+            // the client is alive while the stream is open, from its first client_id
+            let mut client_id = String::new();
+            loop {
+                let req = match stream.message().await {
+                    Ok(Some(req)) => req,
+                    Ok(None) => {
+                        error!("客户端流已关闭");
+                        break;
+                    }
+                    Err(e) => {
+                        error!("接收消息时出错: {}", e);
+                        break;
+                    }
+                };
+                if client_id.is_empty() && !req.client_id.is_empty() {
+                    client_id = req.client_id.clone();
+                    clients.lock().await.stream_opened(&client_id);
+                } else {
+                    clients.lock().await.touch(&client_id);
                 }
-            } {
                 if tx
                     .send(Ok(ResponseMessage {
                         message: format!("Echo: {}", req.message),
@@ -169,6 +1150,7 @@ impl ExampleService for MyExampleService {
                     break;
                 }
             }
+            clients.lock().await.stream_closed(&client_id);
             info!("流结束");
         });
         Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
@@ -1,34 +1,38 @@
-#[derive(Debug)]
-pub struct SharedData {
-    pub counter: u64,
-    pub messages: Vec<String>,
-}
+use std::sync::Arc;
 
-impl SharedData {
-    pub fn new() -> Self {
-        SharedData {
-            counter: 1,
-            messages: Vec::new(),
-        }
-    }
+use log::error;
+use tokio::sync::Mutex;
 
-    pub fn add_message(&mut self, msg: String) -> u64 {
-        let seq_num = self.counter;
-        self.counter += 1;
-        self.messages.push(msg);
-        seq_num
-    }
+use crate::algo::ParentBook;
+use crate::kill_switch::KillSwitch;
+use crate::order_manager::OrderManager;
+use crate::position::PositionBook;
+use crate::store::{Journal, Record};
+use crate::symbol_lists::SymbolLists;
+use crate::throttle::Throttle;
+
+/// Books shared by the FIX session and the gRPC service.
+#[derive(Clone)]
+pub struct Books {
+    pub orders: Arc<Mutex<OrderManager>>,
+    /// Locked after `orders` when both are needed.
+    pub positions: Arc<Mutex<PositionBook>>,
+    /// Locked before `orders` when both are needed.
+    pub parents: Arc<Mutex<ParentBook>>,
+    pub journal: Arc<Journal>,
+    /// Outbound messages of the FIX session.
+    pub throttle: Arc<Mutex<Throttle>>,
+    /// Locked before the other books when several are needed.
+    pub kill_switch: Arc<Mutex<KillSwitch>>,
+    /// Locked after `kill_switch`, before `parents`.
+    pub symbol_lists: Arc<Mutex<SymbolLists>>,
+}
 
-    pub fn get_messages_from(&self, start_seq: u64) -> Vec<SharedData> {
-        let mut result = Vec::new();
-        for (i, msg) in self.messages.iter().enumerate() {
-            if (i as u64 + 1) >= start_seq {
-                let mut sd = SharedData::new();
-                sd.counter = i as u64 + 1;
-                sd.messages.push(msg.clone());
-                result.push(sd);
-            }
+impl Books {
+    /// Journals a fill or event, a failure is logged and the update still applies.
+    pub fn record(&self, record: &Record) {
+        if let Err(e) = self.journal.append(record) {
+            error!("journal {:?} failed: {}", record, e);
         }
-        result
     }
 }
@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::algo::ParentOrder;
+use crate::event_log::{Event, EventLog};
+use crate::idempotency::KeyOutcome;
+use crate::kill_switch::Halts;
+use crate::order_manager::{Fill, Order};
+
+/// Cancel request sent for an order, with the client reference it came with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CancelRequest {
+    pub cl_ord_id: String,
+    pub orig_cl_ord_id: String,
+    pub client_id: String,
+    pub client_ref: Option<String>,
+}
+
+/// One line of the journal.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "record", rename_all = "snake_case")]
+pub enum Record {
+    /// Latest state of an order, replaces the earlier ones.
+    Order(Order),
+    CancelRequest(CancelRequest),
+    /// New fill, or the correction of the fill with the same ExecID.
+    Fill(Fill),
+    Bust {
+        exec_id: String,
+    },
+    /// Execution event pushed to stream subscribers, in sequence order.
+    Event {
+        /// Written on compaction, 0 for the one after the event before.
+        #[serde(default)]
+        seq: u64,
+        message: String,
+        /// When it was added, 0 in journals written before it was kept.
+        #[serde(default)]
+        time_ms: i64,
+    },
+    /// Latest state of a parent order, replaces the earlier ones.
+    Parent(ParentOrder),
+    /// Trading halts in force, replaces the earlier ones.
+    KillSwitch(Halts),
+    /// Outcome of a request with an idempotency key.
+    Idempotency(KeyOutcome),
+}
+
+impl Record {
+    /// Execution event added now.
+    pub fn event(message: String) -> Self {
+        Record::Event {
+            seq: 0,
+            message,
+            time_ms: chrono::Utc::now().timestamp_millis(),
+        }
+    }
+}
+
+/// How long compaction keeps the records only needed for a while.
+#[derive(Debug, Default, Clone)]
+pub struct Retention {
+    /// Outcomes of idempotency keys older than this (ms) are dropped, all of
+    /// them with 0.
+    pub idempotency_ms: i64,
+    /// Latest execution events kept, 0 for all.
+    pub max_events: usize,
+    /// Execution events older than this (ms) are let go, 0 for no limit.
+    pub event_age_ms: i64,
+}
+
+/// State read back from the journal on start.
+#[derive(Debug, Default)]
+pub struct Recovered {
+    pub orders: Vec<Order>,
+    pub cancel_requests: Vec<CancelRequest>,
+    pub fills: Vec<Fill>,
+    /// Numbered on from the first one of the latest compaction, or from 1.
+    pub events: Vec<Event>,
+    pub parents: Vec<ParentOrder>,
+    pub halts: Halts,
+    pub idempotency: Vec<KeyOutcome>,
+}
+
+impl Recovered {
+    fn records(&self) -> impl Iterator<Item = Record> + '_ {
+        self.orders
+            .iter()
+            .cloned()
+            .map(Record::Order)
+            .chain(
+                self.cancel_requests
+                    .iter()
+                    .cloned()
+                    .map(Record::CancelRequest),
+            )
+            .chain(self.fills.iter().cloned().map(Record::Fill))
+            .chain(self.events.iter().map(|event| Record::Event {
+                seq: event.seq,
+                message: event.message.clone(),
+                time_ms: event.time_ms,
+            }))
+            .chain(self.parents.iter().cloned().map(Record::Parent))
+            .chain(
+                (self.halts.gateway.is_some() || !self.halts.accounts.is_empty())
+                    .then(|| Record::KillSwitch(self.halts.clone())),
+            )
+            .chain(self.idempotency.iter().cloned().map(Record::Idempotency))
+    }
+
+    /// Drops what is past its retention at `now_ms`, but for the execution
+    /// events, which are returned. The latest event is kept, the events
+    /// appended after it are numbered on from it.
+    fn trim(&mut self, retention: &Retention, now_ms: i64) -> Vec<Event> {
+        self.idempotency
+            .retain(|o| now_ms - o.at_ms < retention.idempotency_ms);
+        let old = match retention.event_age_ms {
+            0 => 0,
+            age_ms => self
+                .events
+                .iter()
+                .take_while(|e| now_ms - e.time_ms > age_ms)
+                .count(),
+        };
+        let excess = match retention.max_events {
+            0 => 0,
+            n => self.events.len().saturating_sub(n),
+        };
+        let trimmed = old.max(excess).min(self.events.len().saturating_sub(1));
+        self.events.drain(..trimmed).collect()
+    }
+}
+
+/// Append-only journal of orders, fills and execution events, one JSON record
+/// per line. It is replayed and compacted on start, so the order book survives
+/// a crash or restart.
+pub struct Journal {
+    path: String,
+    file: Mutex<Option<File>>,
+}
+
+impl Journal {
+    /// Opens the journal at `path` and returns what it holds, less what is
+    /// past its `retention`; the execution events past it are moved to the
+    /// spill file of `events` first. An empty path gives a journal that keeps
+    /// nothing.
+    pub fn open(
+        path: &str,
+        retention: &Retention,
+        events: &mut EventLog,
+    ) -> io::Result<(Self, Recovered)> {
+        let mut journal = Journal {
+            path: path.to_string(),
+            file: Mutex::new(None),
+        };
+        if path.is_empty() {
+            return Ok((journal, Recovered::default()));
+        }
+
+        let mut recovered = if Path::new(path).exists() {
+            Self::replay(path)?
+        } else {
+            Recovered::default()
+        };
+        let trimmed = recovered.trim(retention, chrono::Utc::now().timestamp_millis());
+        events.spill(&trimmed)?;
+        journal.compact(&recovered)?;
+        info!(
+            "recovered {} orders, {} fills and {} events from {}",
+            recovered.orders.len(),
+            recovered.fills.len(),
+            recovered.events.len(),
+            path
+        );
+        Ok((journal, recovered))
+    }
+
+    fn replay(path: &str) -> io::Result<Recovered> {
+        let mut recovered = Recovered::default();
+        // events journaled before their time was kept count from now
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        let mut order_index = HashMap::new();
+        let mut parent_index = HashMap::new();
+        let mut fills = Vec::new();
+        let mut fill_index = HashMap::new();
+        for (n, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            let record = match serde_json::from_str(&line) {
+                Ok(record) => record,
+                // a crash may leave the last line half written
+                Err(e) => {
+                    warn!("{}:{} skipped: {}", path, n + 1, e);
+                    continue;
+                }
+            };
+            match record {
+                Record::Order(order) => match order_index.get(&order.cl_ord_id) {
+                    Some(&i) => recovered.orders[i] = order,
+                    None => {
+                        order_index.insert(order.cl_ord_id.clone(), recovered.orders.len());
+                        recovered.orders.push(order);
+                    }
+                },
+                Record::CancelRequest(cancel) => recovered.cancel_requests.push(cancel),
+                Record::Fill(fill) => match fill_index.get(&fill.exec_id) {
+                    Some(&i) => fills[i] = Some(fill),
+                    None => {
+                        fill_index.insert(fill.exec_id.clone(), fills.len());
+                        fills.push(Some(fill));
+                    }
+                },
+                Record::Bust { exec_id } => {
+                    if let Some(&i) = fill_index.get(&exec_id) {
+                        fills[i] = None;
+                    }
+                }
+                Record::Event {
+                    seq,
+                    message,
+                    time_ms,
+                } => {
+                    let seq = match seq {
+                        0 => recovered.events.last().map_or(1, |e| e.seq + 1),
+                        seq => seq,
+                    };
+                    let time_ms = match time_ms {
+                        0 => now_ms,
+                        time_ms => time_ms,
+                    };
+                    recovered.events.push(Event {
+                        seq,
+                        time_ms,
+                        message,
+                    })
+                }
+                Record::Parent(parent) => match parent_index.get(&parent.parent_id) {
+                    Some(&i) => recovered.parents[i] = parent,
+                    None => {
+                        parent_index.insert(parent.parent_id.clone(), recovered.parents.len());
+                        recovered.parents.push(parent);
+                    }
+                },
+                Record::KillSwitch(halts) => recovered.halts = halts,
+                Record::Idempotency(outcome) => recovered.idempotency.push(outcome),
+            }
+        }
+        recovered.fills = fills.into_iter().flatten().collect();
+        Ok(recovered)
+    }
+
+    /// Rewrites the journal with one record per order, fill and parent order,
+    /// the execution events, the trading halts and the idempotency key
+    /// outcomes.
+    fn compact(&mut self, recovered: &Recovered) -> io::Result<()> {
+        if let Some(dir) = Path::new(&self.path).parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = format!("{}.tmp", self.path);
+        let mut file = File::create(&tmp)?;
+        for record in recovered.records() {
+            writeln!(file, "{}", serde_json::to_string(&record)?)?;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        *self.file.get_mut().unwrap() = Some(OpenOptions::new().append(true).open(&self.path)?);
+        Ok(())
+    }
+
+    pub fn append(&self, record: &Record) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        let Some(file) = file.as_mut() else {
+            return Ok(());
+        };
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfg::EventLogCfg;
+
+    #[test]
+    fn compaction_spills_older_events_and_keeps_their_numbers_and_times() {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("store_{}.jsonl", std::process::id()));
+        let path = path.to_str().unwrap();
+        let spill = dir.join(format!("store_{}.spill", std::process::id()));
+        let spill = spill.to_str().unwrap();
+        let event_log = || {
+            EventLog::new(&EventLogCfg {
+                max_events: 2,
+                max_age_secs: 0,
+                spill_file: spill.to_string(),
+            })
+            .unwrap()
+        };
+        let now_ms = chrono::Utc::now().timestamp_millis();
+        {
+            let mut events = event_log();
+            let (journal, _) = Journal::open(path, &Retention::default(), &mut events).unwrap();
+            for (i, age_ms) in [(1, 90_000), (2, 30_000), (3, 20_000), (4, 10_000)] {
+                let record = Record::Event {
+                    seq: 0,
+                    message: format!("event {i}"),
+                    time_ms: now_ms - age_ms,
+                };
+                journal.append(&record).unwrap();
+            }
+            // journaled before event times were kept
+            journal
+                .append(&Record::Event {
+                    seq: 0,
+                    message: "event 5".to_string(),
+                    time_ms: 0,
+                })
+                .unwrap();
+        }
+        let retention = Retention {
+            max_events: 2,
+            event_age_ms: 60_000,
+            ..Default::default()
+        };
+        let mut events = event_log();
+        let (journal, recovered) = Journal::open(path, &retention, &mut events).unwrap();
+        let kept: Vec<_> = recovered
+            .events
+            .iter()
+            .map(|e| (e.seq, e.message.as_str(), e.time_ms))
+            .collect();
+        assert_eq!(kept[0], (4, "event 4", now_ms - 10_000));
+        assert_eq!(kept[1].1, "event 5");
+        assert!(kept[1].2 >= now_ms);
+        // the others are in the spill file
+        let spilled: Vec<_> = events.get_from(1, 10).into_iter().map(|e| e.seq).collect();
+        assert_eq!(spilled, [1, 2, 3]);
+
+        // compacted: numbered on from the events kept
+        journal
+            .append(&Record::event("event 6".to_string()))
+            .unwrap();
+        drop(journal);
+        let (_, again) = Journal::open(path, &Retention::default(), &mut event_log()).unwrap();
+        let seqs: Vec<_> = again.events.iter().map(|e| e.seq).collect();
+        assert_eq!(seqs, [4, 5, 6]);
+        assert_eq!(again.events[0].time_ms, now_ms - 10_000);
+        fs::remove_file(path).unwrap();
+        fs::remove_file(spill).unwrap();
+    }
+}
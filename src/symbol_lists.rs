@@ -0,0 +1,191 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::server::fantasy::{SymbolList, SymbolListGroup};
+
+/// Account that puts a group over every account.
+pub const ALL_ACCOUNTS: &str = "*";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Group {
+    /// Accounts the lists apply to, `*` for all.
+    #[serde(default)]
+    pub accounts: BTreeSet<String>,
+    /// Symbols orders are blocked in.
+    #[serde(default)]
+    pub restricted: BTreeSet<String>,
+    /// Symbols orders are allowed in but flagged.
+    #[serde(default)]
+    pub watch: BTreeSet<String>,
+}
+
+/// List an order's symbol is on, with the group it comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Listed {
+    Restricted(String),
+    Watch(String),
+}
+
+/// Restricted and watch list symbols per account group, kept in a YAML file
+/// and updated live by the admin RPC, which writes the file back.
+#[derive(Debug, Default)]
+pub struct SymbolLists {
+    path: String,
+    groups: BTreeMap<String, Group>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ListsFile {
+    #[serde(default)]
+    groups: BTreeMap<String, Group>,
+}
+
+impl SymbolLists {
+    /// Loads the lists from `path`, an empty path gives empty lists kept in memory.
+    pub fn load(path: &str) -> io::Result<Self> {
+        if path.is_empty() {
+            return Ok(SymbolLists::default());
+        }
+        let file: ListsFile = serde_yaml::from_str(&fs::read_to_string(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{path}: {e}")))?;
+        info!(
+            "loaded {} symbol list groups from {}",
+            file.groups.len(),
+            path
+        );
+        Ok(SymbolLists {
+            path: path.to_string(),
+            groups: file.groups,
+        })
+    }
+
+    /// The list `symbol` is on for `account`, a restricted list wins over a
+    /// watch list.
+    pub fn check(&self, account: &str, symbol: &str) -> Option<Listed> {
+        let groups: Vec<_> = self
+            .groups
+            .iter()
+            .filter(|(_, g)| g.accounts.contains(account) || g.accounts.contains(ALL_ACCOUNTS))
+            .collect();
+        let restricted = groups
+            .iter()
+            .find(|(_, g)| g.restricted.contains(symbol))
+            .map(|(name, _)| Listed::Restricted(name.to_string()));
+        restricted.or_else(|| {
+            groups
+                .iter()
+                .find(|(_, g)| g.watch.contains(symbol))
+                .map(|(name, _)| Listed::Watch(name.to_string()))
+        })
+    }
+
+    /// Puts `symbol` on `list` of `group` (off both lists for
+    /// `SymbolList::Unspecified`), and replaces the group's accounts when
+    /// `accounts` is not empty. The group is created if needed.
+    pub fn update(
+        &mut self,
+        group: &str,
+        symbol: &str,
+        list: SymbolList,
+        accounts: &[String],
+    ) -> io::Result<()> {
+        let mut groups = self.groups.clone();
+        let entry = groups.entry(group.to_string()).or_default();
+        if !symbol.is_empty() {
+            entry.restricted.remove(symbol);
+            entry.watch.remove(symbol);
+            match list {
+                SymbolList::Restricted => entry.restricted.insert(symbol.to_string()),
+                SymbolList::Watch => entry.watch.insert(symbol.to_string()),
+                SymbolList::Unspecified => false,
+            };
+        }
+        if !accounts.is_empty() {
+            entry.accounts = accounts.iter().cloned().collect();
+        }
+        self.save(&groups)?;
+        self.groups = groups;
+        Ok(())
+    }
+
+    pub fn reports(&self) -> Vec<SymbolListGroup> {
+        self.groups
+            .iter()
+            .map(|(name, g)| SymbolListGroup {
+                group: name.clone(),
+                accounts: g.accounts.iter().cloned().collect(),
+                restricted: g.restricted.iter().cloned().collect(),
+                watch: g.watch.iter().cloned().collect(),
+            })
+            .collect()
+    }
+
+    /// Writes the lists to the file first, so an update is not live unless it
+    /// survives a restart.
+    fn save(&self, groups: &BTreeMap<String, Group>) -> io::Result<()> {
+        if self.path.is_empty() {
+            return Ok(());
+        }
+        let file = ListsFile {
+            groups: groups.clone(),
+        };
+        let yaml = serde_yaml::to_string(&file)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let tmp = format!("{}.tmp", self.path);
+        fs::write(&tmp, yaml)?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accounts(accounts: &[&str]) -> Vec<String> {
+        accounts.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn restricted_list_wins_over_watch_list() {
+        let mut lists = SymbolLists::default();
+        lists
+            .update(
+                "all",
+                "USDTRY",
+                SymbolList::Watch,
+                &accounts(&[ALL_ACCOUNTS]),
+            )
+            .unwrap();
+        lists
+            .update(
+                "desk",
+                "USDTRY",
+                SymbolList::Restricted,
+                &accounts(&["acc"]),
+            )
+            .unwrap();
+
+        assert_eq!(
+            lists.check("acc", "USDTRY"),
+            Some(Listed::Restricted("desk".to_string()))
+        );
+        assert_eq!(
+            lists.check("other", "USDTRY"),
+            Some(Listed::Watch("all".to_string()))
+        );
+        assert_eq!(lists.check("acc", "USDJPY"), None);
+
+        // off the restricted list, the watch list applies again
+        lists
+            .update("desk", "USDTRY", SymbolList::Unspecified, &[])
+            .unwrap();
+        assert_eq!(
+            lists.check("acc", "USDTRY"),
+            Some(Listed::Watch("all".to_string()))
+        );
+    }
+}
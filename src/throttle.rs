@@ -0,0 +1,167 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::cfg::{ThrottleBreach, ThrottleCfg};
+use crate::server::fantasy::ThrottleStatus;
+
+/// Window the utilisation of the throttle is measured over.
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Token bucket limiting the messages sent on the FIX session: `burst` tokens,
+/// refilled at `messages_per_sec`, one taken by every message.
+#[derive(Debug)]
+pub struct Throttle {
+    rate: f64,
+    burst: f64,
+    on_breach: ThrottleBreach,
+    tokens: f64,
+    refilled: Instant,
+    /// Send times within the last `WINDOW`.
+    recent: VecDeque<Instant>,
+    sent: u64,
+    delayed: u64,
+    rejected: u64,
+    queued: usize,
+}
+
+impl Throttle {
+    pub fn new(cfg: &ThrottleCfg) -> Self {
+        let burst = cfg.burst.max(1) as f64;
+        Throttle {
+            rate: cfg.messages_per_sec.max(0.0),
+            burst,
+            on_breach: cfg.on_breach,
+            tokens: burst,
+            refilled: Instant::now(),
+            recent: VecDeque::new(),
+            sent: 0,
+            delayed: 0,
+            rejected: 0,
+            queued: 0,
+        }
+    }
+
+    pub fn on_breach(&self) -> ThrottleBreach {
+        self.on_breach
+    }
+
+    /// Takes a token for one message, false when there is none left.
+    pub fn try_acquire(&mut self, now: Instant) -> bool {
+        if self.rate > 0.0 {
+            self.refill(now);
+            if self.tokens < 1.0 {
+                return false;
+            }
+            self.tokens -= 1.0;
+        }
+        self.sent += 1;
+        self.forget_before(now);
+        self.recent.push_back(now);
+        true
+    }
+
+    /// How long until the next token, counts the message as delayed.
+    pub fn delay(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        self.delayed += 1;
+        Duration::from_secs_f64(((1.0 - self.tokens) / self.rate).max(0.0))
+    }
+
+    pub fn count_rejected(&mut self) {
+        self.rejected += 1;
+    }
+
+    /// Messages waiting in the dispatch queue.
+    pub fn set_queued(&mut self, queued: usize) {
+        self.queued = queued;
+    }
+
+    pub fn status(&mut self, now: Instant) -> ThrottleStatus {
+        if self.rate > 0.0 {
+            self.refill(now);
+        }
+        self.forget_before(now);
+        let sent_last_sec = self.recent.len() as u32;
+        ThrottleStatus {
+            messages_per_sec: self.rate,
+            burst: self.burst as u32,
+            on_breach: format!("{:?}", self.on_breach),
+            available: if self.rate > 0.0 {
+                self.tokens
+            } else {
+                self.burst
+            },
+            sent_last_sec,
+            utilisation: if self.rate > 0.0 {
+                sent_last_sec as f64 / self.rate
+            } else {
+                0.0
+            },
+            queued: self.queued as u32,
+            sent: self.sent,
+            delayed: self.delayed,
+            rejected: self.rejected,
+        }
+    }
+
+    fn forget_before(&mut self, now: Instant) {
+        while self
+            .recent
+            .front()
+            .is_some_and(|sent| now.saturating_duration_since(*sent) > WINDOW)
+        {
+            self.recent.pop_front();
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.refilled = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn throttle(messages_per_sec: f64, burst: u32) -> Throttle {
+        Throttle::new(&ThrottleCfg {
+            messages_per_sec,
+            burst,
+            on_breach: ThrottleBreach::Queue,
+        })
+    }
+
+    #[test]
+    fn burst_then_refill_at_the_rate() {
+        let mut throttle = throttle(10.0, 2);
+        let start = Instant::now();
+        assert!(throttle.try_acquire(start));
+        assert!(throttle.try_acquire(start));
+        assert!(!throttle.try_acquire(start));
+        let delay = throttle.delay(start);
+        assert!((delay.as_secs_f64() - 0.1).abs() < 1e-6);
+
+        assert!(throttle.try_acquire(start + Duration::from_millis(100)));
+        assert!(!throttle.try_acquire(start + Duration::from_millis(150)));
+        // refilled up to the burst only
+        let later = start + Duration::from_secs(10);
+        assert!(throttle.try_acquire(later));
+        assert!(throttle.try_acquire(later));
+        assert!(!throttle.try_acquire(later));
+
+        let status = throttle.status(later);
+        assert_eq!(
+            (status.sent, status.delayed, status.sent_last_sec),
+            (5, 1, 2)
+        );
+    }
+
+    #[test]
+    fn no_rate_is_no_limit() {
+        let mut throttle = throttle(0.0, 0);
+        let now = Instant::now();
+        assert!((0..1000).all(|_| throttle.try_acquire(now)));
+    }
+}
@@ -50,14 +50,18 @@ class FixServer(fix.Application):
             execution_report = fix.Message()
             execution_report.getHeader().setField(fix.MsgType(fix.MsgType_ExecutionReport))
             execution_report.setField(fix.OrderID(''.join(random.choices(string.ascii_uppercase + string.digits, k=10))))  # 订单编号
+            execution_report.setField(order_id)  # 客户端订单编号
             execution_report.setField(symbol)  # 交易的 Symbol
-            execution_report.setField(fix.ExecID("54321"))  # 执行编号
+            execution_report.setField(fix.ExecID(''.join(random.choices(string.ascii_uppercase + string.digits, k=10))))  # 执行编号，每个回报唯一
+            execution_report.setField(fix.ExecTransType(fix.ExecTransType_NEW))  # 执行事务类型
             execution_report.setField(fix.ExecType(fix.ExecType_FILL))  # 订单执行类型
             execution_report.setField(fix.OrdStatus(fix.OrdStatus_FILLED))  # 订单状态：已成交
             execution_report.setField(side)  # 买卖方向
             execution_report.setField(fix.LeavesQty(0))  # 剩余未成交数量
             execution_report.setField(fix.CumQty(100))  # 累积成交数量
             execution_report.setField(fix.AvgPx(150.25))  # 平均成交价
+            execution_report.setField(fix.LastShares(100))  # 本次成交数量
+            execution_report.setField(fix.LastPx(150.25))  # 本次成交价
             execution_report.setField(fix.Text("hello world"))  # 平均成交价
             # 发送执行报告
             fix.Session.sendToTarget(execution_report, sessionID)